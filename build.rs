@@ -1,5 +1,28 @@
-// Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
+#[toml_cfg::toml_config]
+pub struct Config {
+    #[default("")]
+    ota_public_key: &'static str,
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=cfg.toml");
+
+    // The constant `CONFIG` is auto-generated by `toml_config`.
+    let key = CONFIG.ota_public_key;
+    if key.is_empty() {
+        println!("cargo:warning=No `ota_public_key` in cfg.toml, every OTA update will be refused");
+    } else if key.len() != 64 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(
+            "`ota_public_key` in cfg.toml must be a hex encoded 32 byte Ed25519 key".into(),
+        );
+    }
+
+    // The library is also built for the host, where there is no ESP-IDF
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("espidf") {
+        return Ok(());
+    }
+
+    // Necessary because of this issue: https://github.com/rust-lang/cargo/issues/9641
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")?;
     Ok(())
@@ -0,0 +1,101 @@
+//! Signs OTA manifests for the key compiled in as `ota_public_key`.
+//!
+//! Runs on the host:
+//! `cargo run --example manifest --target x86_64-unknown-linux-gnu -- <command>`
+//!
+//! - `keygen <key file>` writes a new secret key to `<key file>` and prints
+//!   the public key to set as `ota_public_key` in `cfg.toml`.
+//! - `release <image> <link>` prints the manifest entry of a release, with
+//!   the version from the image's app descriptor, for the image served at
+//!   `<link>`. Make the image with `espflash save-image` after every build.
+//! - `sign <key file> <manifest>` prints the signature to put in the
+//!   manifest's `signature` field. It covers every other field, so sign again
+//!   after any change.
+//!
+//! The `manifest.json` in this repository is signed with `examples/keys/test.key`,
+//! whose public key is `examples/keys/test.pub`. Both are public, only use
+//! them to try the updater out.
+
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::SigningKey;
+use esp_ota_template::{
+    image, signature,
+    updater::{ArtifactJson, UpdateJson},
+};
+use semver::Version;
+use sha2::{Digest, Sha256};
+use std::{env, fs, io::Read};
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args[..] {
+        ["keygen", key_file] => keygen(key_file),
+        ["release", image, link] => release(image, link),
+        ["sign", key_file, manifest] => sign(key_file, manifest),
+        _ => bail!(
+            "Usage: manifest keygen <key file> | release <image> <link> | sign <key file> <manifest>"
+        ),
+    }
+}
+
+fn keygen(key_file: &str) -> Result<()> {
+    let mut secret = [0_u8; 32];
+    fs::File::open("/dev/urandom")?.read_exact(&mut secret)?;
+    let key = SigningKey::from_bytes(&secret);
+
+    fs::write(key_file, hex::encode(secret) + "\n")
+        .with_context(|| format!("Writing {}", key_file))?;
+    println!("{}", hex::encode(key.verifying_key().to_bytes()));
+    Ok(())
+}
+
+fn release(image: &str, link: &str) -> Result<()> {
+    let data = fs::read(image).with_context(|| format!("Reading {}", image))?;
+    let info = image::parse_header(&data)?;
+    // Devices refuse images whose descriptor does not hold the announced version.
+    let version = Version::parse(&info.version)
+        .with_context(|| format!("App descriptor version {:?} is not semver", info.version))?;
+    let Some(chip) = chip_name(info.chip_id) else {
+        bail!("Unknown chip id {}", info.chip_id);
+    };
+
+    let release = UpdateJson {
+        version: version.to_string(),
+        artifacts: vec![ArtifactJson {
+            chip: chip.to_owned(),
+            boards: Vec::new(),
+            link: link.to_owned(),
+            mirrors: Vec::new(),
+            sha256: hex::encode(Sha256::digest(&data)),
+            size: data.len() as u64,
+        }],
+        rollout: None,
+        rollout_seed: None,
+        min_from_version: None,
+        mandatory: false,
+    };
+    println!("{}", serde_json::to_string_pretty(&release)?);
+    Ok(())
+}
+
+/// ESP-IDF target name of an `esp_chip_id_t`.
+fn chip_name(chip_id: u16) -> Option<&'static str> {
+    Some(match chip_id {
+        0 => "esp32",
+        2 => "esp32s2",
+        5 => "esp32c3",
+        9 => "esp32s3",
+        12 => "esp32c2",
+        13 => "esp32c6",
+        16 => "esp32h2",
+        _ => return None,
+    })
+}
+
+fn sign(key_file: &str, manifest: &str) -> Result<()> {
+    let key = fs::read_to_string(key_file).with_context(|| format!("Reading {}", key_file))?;
+    let body = fs::read(manifest).with_context(|| format!("Reading {}", manifest))?;
+    println!("{}", signature::sign_manifest(&body, &key)?);
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use anyhow::Result;
+use semver::Version;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{channel::Channel, reboot::RebootHandshake, state::StateHandle};
+
+/// What the application thread gets to follow and reconfigure the updater with.
+#[derive(Clone)]
+pub struct Context {
+    pub state: StateHandle,
+    pub reboot: RebootHandshake,
+    pub settings: Arc<Mutex<dyn UpdaterSettings>>,
+}
+
+/// Overrides of the updater's build-time settings, kept across reboots and
+/// picked up from the next poll on.
+pub trait UpdaterSettings: Send {
+    fn set_manifest_url(&mut self, url: &str) -> Result<()>;
+
+    fn set_poll_interval(&mut self, interval: Duration) -> Result<()>;
+
+    fn set_poll_jitter(&mut self, jitter: Duration) -> Result<()>;
+
+    /// Turns polling off or back on.
+    fn set_enabled(&mut self, enabled: bool) -> Result<()>;
+
+    /// Also set by the updater when a signed manifest assigns this device
+    /// to a channel.
+    fn set_channel(&mut self, channel: Channel) -> Result<()>;
+
+    /// Keeps the device from updating past `version`.
+    fn set_pin(&mut self, version: &Version) -> Result<()>;
+
+    fn clear_pin(&mut self) -> Result<()>;
+
+    /// Drops every override, going back to the build-time defaults.
+    fn reset(&mut self) -> Result<()>;
+}
+
+/// The firmware's own work, and the hooks through which the updater asks it
+/// about updates.
+///
+/// `init` runs once on the main thread, `run` then gets a thread of its own
+/// while the other hooks are called from the main and updater threads.
+pub trait Application: Send + Sync + 'static {
+    fn init(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Does the application's work, returning once `ctx.reboot` is
+    /// acknowledged or on failure.
+    fn run(&self, ctx: Context) -> Result<()>;
+
+    /// Run on the first boot of a new image, before it is marked valid.
+    /// Returning an error rolls back to the previous image.
+    fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called before `version` is downloaded. Returning an error postpones the
+    /// update to the next poll.
+    fn before_update(&self, _version: &Version) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once a new image, now `version`, has passed its health check.
+    fn after_update(&self, _version: &Version) {}
+
+    /// Called on the boot after the previous image was rolled back.
+    fn on_rollback(&self, _reason: &str) {}
+
+    /// Called before `run` on the first boot after `run` failed or panicked,
+    /// with the last reason it did.
+    fn on_crash(&self, _reason: &str) {}
+}
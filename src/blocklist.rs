@@ -0,0 +1,61 @@
+use core::fmt;
+
+/// How many images are remembered, the oldest is forgotten first.
+const MAX_LEN: usize = 4;
+
+/// An image that was rolled back on this device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockedImage {
+    pub version: String,
+    pub sha256: String,
+}
+
+/// Images rolled back on this device, passed over when picking an update so
+/// one that failed is not installed again and again. A release published
+/// again with another image, and so another digest, is not blocked.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Blocklist(Vec<BlockedImage>);
+
+impl Blocklist {
+    /// Reads a list written with `Display`, skipping entries it cannot make sense of.
+    pub fn parse(s: &str) -> Blocklist {
+        let images = s
+            .split(',')
+            .filter_map(|entry| {
+                let (version, sha256) = entry.trim().split_once(' ')?;
+                Some(BlockedImage {
+                    version: version.to_owned(),
+                    sha256: sha256.to_owned(),
+                })
+            })
+            .collect();
+        Blocklist(images)
+    }
+
+    pub fn block(&mut self, image: BlockedImage) {
+        self.0
+            .retain(|blocked| !blocked.sha256.eq_ignore_ascii_case(&image.sha256));
+        self.0.push(image);
+        if self.0.len() > MAX_LEN {
+            self.0.remove(0);
+        }
+    }
+
+    pub fn contains(&self, sha256: &str) -> bool {
+        self.0
+            .iter()
+            .any(|blocked| blocked.sha256.eq_ignore_ascii_case(sha256))
+    }
+}
+
+impl fmt::Display for Blocklist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, image) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{} {}", image.version, image.sha256)?;
+        }
+        Ok(())
+    }
+}
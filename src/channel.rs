@@ -0,0 +1,57 @@
+use core::{fmt, str::FromStr};
+use serde::{Deserialize, Serialize};
+
+/// Release track a device follows. Only `Stable` refuses pre-release versions.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    #[default]
+    Stable,
+    Beta,
+    Dev,
+}
+
+impl Channel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Channel::Stable => "stable",
+            Channel::Beta => "beta",
+            Channel::Dev => "dev",
+        }
+    }
+
+    pub fn allows_prerelease(&self) -> bool {
+        *self != Channel::Stable
+    }
+}
+
+impl fmt::Display for Channel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A channel name other than `stable`, `beta` or `dev`.
+#[derive(Debug)]
+pub struct UnknownChannel(pub String);
+
+impl fmt::Display for UnknownChannel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown release channel: {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownChannel {}
+
+impl FromStr for Channel {
+    type Err = UnknownChannel;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stable" => Ok(Channel::Stable),
+            "beta" => Ok(Channel::Beta),
+            "dev" => Ok(Channel::Dev),
+            _ => Err(UnknownChannel(s.to_owned())),
+        }
+    }
+}
@@ -0,0 +1,25 @@
+use std::{
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// 2023-01-01, the clock has not been set by SNTP while it reads earlier.
+const MIN_VALID_TIME: u64 = 1_672_531_200;
+
+/// Current Unix time, once SNTP has set the clock.
+pub fn now() -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    (now >= MIN_VALID_TIME).then_some(now)
+}
+
+/// Waits up to `timeout` for the clock to be set.
+pub fn wait_set(timeout: Duration) -> Option<u64> {
+    let start = Instant::now();
+    loop {
+        match now() {
+            Some(now) => return Some(now),
+            None if start.elapsed() >= timeout => return None,
+            None => thread::sleep(Duration::from_millis(500)),
+        }
+    }
+}
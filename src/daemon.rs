@@ -0,0 +1,219 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_ota_template::{
+    app::{Application, Context, UpdaterSettings},
+    error::OtaError,
+    state::UpdateState,
+    updater::{DeviceInfo, Target, Updater},
+};
+use log::{error, info, warn};
+use semver::Version;
+use std::{sync::Arc, thread, time::Duration};
+
+use crate::{
+    clock, device, health,
+    http::EspTransport,
+    new_updater,
+    nvs_progress::NvsProgressStore,
+    nvs_sequence::NvsSequenceStore,
+    settings::{Settings, SettingsStore},
+    slot::OtaSlot,
+    CONFIG,
+};
+
+const BACKOFF_BASE: Duration = Duration::from_secs(5);
+const BACKOFF_MAX: Duration = Duration::from_secs(10 * 60);
+const RESPAWN_DELAY: Duration = Duration::from_secs(10);
+
+/// Exponential backoff capped at `BACKOFF_MAX`, with the upper half jittered
+/// so a fleet coming back online does not poll in lockstep.
+fn backoff(failures: u32) -> Duration {
+    let exp = BACKOFF_BASE.saturating_mul(1 << failures.saturating_sub(1).min(16));
+    let delay = exp.min(BACKOFF_MAX);
+    delay / 2 + (delay / 2).mul_f32(random())
+}
+
+/// Regular poll interval plus a random share of the configured jitter.
+fn poll_delay(settings: &Settings) -> Duration {
+    settings.poll_interval + settings.poll_jitter.mul_f32(random())
+}
+
+fn random() -> f32 {
+    let random = unsafe { esp_idf_sys::esp_random() };
+    random as f32 / u32::MAX as f32
+}
+
+/// Starts the updater on its own thread, respawning it should it ever panic.
+pub fn spawn(
+    version: Version,
+    nvs: EspDefaultNvsPartition,
+    ctx: Context,
+    app: Arc<dyn Application>,
+) {
+    thread::spawn(move || loop {
+        let worker = {
+            let version = version.clone();
+            let nvs = nvs.clone();
+            let ctx = ctx.clone();
+            let app = app.clone();
+            thread::spawn(move || run(version, nvs, ctx, app))
+        };
+        let reason = match worker.join() {
+            Ok(Err(e)) => format!("Updater stopped: {}", e),
+            Ok(Ok(())) => "Updater stopped".to_owned(),
+            Err(_) => "Updater panicked".to_owned(),
+        };
+        error!("{}", reason);
+        ctx.state.set(UpdateState::Failed { reason });
+        thread::sleep(RESPAWN_DELAY);
+        info!("Restarting updater");
+    });
+}
+
+fn run(
+    version: Version,
+    nvs: EspDefaultNvsPartition,
+    ctx: Context,
+    app: Arc<dyn Application>,
+) -> Result<()> {
+    let mut worker = Worker {
+        updater: new_updater(ctx.state.clone()),
+        settings: SettingsStore::new(nvs.clone())?,
+        progress: NvsProgressStore::new(nvs.clone())?,
+        sequences: NvsSequenceStore::new(nvs.clone())?,
+        device_id: device::id()?,
+        target: device::target(),
+        version,
+        rejected: None,
+        nvs,
+        ctx,
+        app,
+    };
+    let mut failures = 0;
+
+    loop {
+        // Reloaded every round so runtime changes apply without a restart.
+        let settings = worker.settings.load()?;
+        if !settings.enabled {
+            thread::sleep(poll_delay(&settings));
+            continue;
+        }
+
+        let state = &worker.ctx.state;
+        let delay = match worker.poll(&settings) {
+            Ok(()) => {
+                failures = 0;
+                state.set_unless_rolled_back(UpdateState::Idle);
+                poll_delay(&settings)
+            }
+            Err(e) if e.is_transient() => {
+                failures += 1;
+                let delay = backoff(failures);
+                warn!(
+                    "Update attempt {} failed: {}, retrying in {:?}",
+                    failures, e, delay
+                );
+                state.set_unless_rolled_back(UpdateState::failed(&e));
+                delay
+            }
+            Err(e) => {
+                failures = 0;
+                error!("Update failed: {}", e);
+                state.set_unless_rolled_back(UpdateState::failed(&e));
+                poll_delay(&settings)
+            }
+        };
+
+        thread::sleep(delay);
+    }
+}
+
+struct Worker {
+    updater: Updater<EspTransport>,
+    settings: SettingsStore,
+    progress: NvsProgressStore,
+    sequences: NvsSequenceStore,
+    device_id: String,
+    target: Target,
+    version: Version,
+    /// Image whose download failed for good, skipped until the manifest changes.
+    rejected: Option<String>,
+    nvs: EspDefaultNvsPartition,
+    ctx: Context,
+    app: Arc<dyn Application>,
+}
+
+impl Worker {
+    fn poll(&mut self, settings: &Settings) -> Result<(), OtaError> {
+        let manifest = self.updater.fetch_manifest(
+            &settings.manifest_urls(),
+            &mut self.sequences,
+            clock::now(),
+        )?;
+
+        let mut channel = settings.channel;
+        if let Some(assigned) = manifest
+            .assignment(&self.device_id)
+            .filter(|assigned| *assigned != channel)
+        {
+            info!(
+                "Manifest moves this device from {} to {}",
+                channel, assigned
+            );
+            self.settings
+                .set_channel(assigned)
+                .map_err(OtaError::Flash)?;
+            channel = assigned;
+        }
+
+        let blocklist = health::blocklist(&self.nvs).map_err(OtaError::Flash)?;
+        let device = DeviceInfo {
+            id: &self.device_id,
+            target: &self.target,
+            current: &self.version,
+            pin: settings.pin.as_ref(),
+            blocklist: &blocklist,
+        };
+        let Some(update) = manifest.update(channel, &device)? else {
+            return Ok(());
+        };
+        info!("{} available, running {}", update.version, self.version);
+        if self.rejected.as_ref() == Some(&update.sha256) {
+            return Ok(());
+        }
+
+        self.ctx.state.set(UpdateState::UpdateAvailable {
+            version: update.version.clone(),
+        });
+        if let Err(e) = self.app.before_update(&update.version) {
+            info!("Application postponed update to {}: {}", update.version, e);
+            return Ok(());
+        }
+        let mut slot = OtaSlot::next().map_err(OtaError::Flash)?;
+        let result = self
+            .updater
+            .ota_update(&update, &mut slot, &mut self.progress);
+        if let Err(e) = &result {
+            if !e.is_transient() {
+                self.rejected = Some(update.sha256.clone());
+            }
+        }
+        result?;
+        // Without it a rollback of the new image cannot block it, not worth
+        // keeping the image from booting over.
+        if let Err(e) =
+            health::record_installed(&self.nvs, &update.version.to_string(), &update.sha256)
+        {
+            warn!("Could not record the installed image: {}", e);
+        }
+
+        // The new image boots either way, the application only gets the chance
+        // to wrap up first.
+        self.ctx.reboot.request(
+            Duration::from_secs(CONFIG.reboot_grace_secs),
+            Duration::from_secs(CONFIG.reboot_max_defer_secs),
+        );
+        info!("Restarting into {}", update.version);
+        unsafe { esp_idf_sys::esp_restart() }
+    }
+}
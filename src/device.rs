@@ -0,0 +1,21 @@
+use anyhow::Result;
+use esp_idf_sys::{esp, esp_efuse_mac_get_default};
+use esp_ota_template::updater::Target;
+
+use crate::CONFIG;
+
+/// Identifies this chip in manifests: its factory MAC address, in hex.
+pub fn id() -> Result<String> {
+    let mut mac = [0_u8; 6];
+    esp!(unsafe { esp_efuse_mac_get_default(mac.as_mut_ptr()) })?;
+    Ok(hex::encode(mac))
+}
+
+/// The chip this firmware was built for and the board set in `cfg.toml`.
+pub fn target() -> Target {
+    let chip = core::str::from_utf8(esp_idf_sys::CONFIG_IDF_TARGET).unwrap_or_default();
+    Target {
+        chip: chip.trim_end_matches('\0').to_owned(),
+        board: CONFIG.board.to_owned(),
+    }
+}
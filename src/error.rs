@@ -0,0 +1,213 @@
+use core::fmt;
+
+use crate::{image::ImageError, integrity::IntegrityError, signature::SignatureError};
+
+/// Why a check or an update did not go through.
+#[derive(Debug)]
+pub enum OtaError {
+    /// The server could not be reached or the connection failed midway.
+    Network(anyhow::Error),
+    /// The image stream ended early, what was received is kept for resuming.
+    Incomplete {
+        expected: u64,
+        received: u64,
+    },
+    /// The server answered with something other than 2xx.
+    HttpStatus(u16),
+    Manifest(ManifestError),
+    Version(semver::Error),
+    Integrity(IntegrityError),
+    /// Writing the inactive slot or the download progress failed.
+    Flash(anyhow::Error),
+    /// The update is well formed but not one this device may install.
+    Policy(PolicyError),
+    /// The manifest is not current, or the clock is not set to tell.
+    Stale(StaleError),
+}
+
+impl OtaError {
+    /// Whether trying again later may succeed, as opposed to the same
+    /// manifest or image failing the same way every time.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            OtaError::Network(_)
+            | OtaError::Incomplete { .. }
+            | OtaError::Flash(_)
+            | OtaError::Stale(_) => true,
+            OtaError::HttpStatus(status) => *status >= 500 || *status == 408 || *status == 429,
+            OtaError::Manifest(e) => {
+                matches!(
+                    e,
+                    ManifestError::Empty
+                        | ManifestError::Truncated { .. }
+                        | ManifestError::Overlong { .. }
+                )
+            }
+            OtaError::Version(_) | OtaError::Integrity(_) | OtaError::Policy(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for OtaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtaError::Network(e) => write!(f, "Network error: {}", e),
+            OtaError::Incomplete { expected, received } => {
+                write!(f, "Download ended after {} of {} bytes", received, expected)
+            }
+            OtaError::HttpStatus(status) => write!(f, "Unexpected response code: {}", status),
+            OtaError::Manifest(e) => e.fmt(f),
+            OtaError::Version(e) => write!(f, "Invalid version: {}", e),
+            OtaError::Integrity(e) => e.fmt(f),
+            OtaError::Flash(e) => write!(f, "Flash error: {}", e),
+            OtaError::Policy(e) => e.fmt(f),
+            OtaError::Stale(e) => write!(f, "Stale metadata: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OtaError {}
+
+impl From<ManifestError> for OtaError {
+    fn from(e: ManifestError) -> Self {
+        OtaError::Manifest(e)
+    }
+}
+
+impl From<SignatureError> for OtaError {
+    fn from(e: SignatureError) -> Self {
+        OtaError::Manifest(ManifestError::Signature(e))
+    }
+}
+
+impl From<semver::Error> for OtaError {
+    fn from(e: semver::Error) -> Self {
+        OtaError::Version(e)
+    }
+}
+
+impl From<IntegrityError> for OtaError {
+    fn from(e: IntegrityError) -> Self {
+        OtaError::Integrity(e)
+    }
+}
+
+impl From<PolicyError> for OtaError {
+    fn from(e: PolicyError) -> Self {
+        OtaError::Policy(e)
+    }
+}
+
+impl From<StaleError> for OtaError {
+    fn from(e: StaleError) -> Self {
+        OtaError::Stale(e)
+    }
+}
+
+impl From<ImageError> for OtaError {
+    fn from(e: ImageError) -> Self {
+        OtaError::Policy(PolicyError::Image(e))
+    }
+}
+
+/// A manifest that could not be read, authenticated or parsed.
+#[derive(Debug)]
+pub enum ManifestError {
+    Empty,
+    /// Bigger than `manifest_max_size`, by Content-Length or by what was received.
+    TooLarge {
+        limit: u64,
+        size: u64,
+    },
+    /// The connection ended before Content-Length bytes arrived.
+    Truncated {
+        expected: u64,
+        received: u64,
+    },
+    /// More bytes arrived than Content-Length announced.
+    Overlong {
+        expected: u64,
+    },
+    Signature(SignatureError),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for ManifestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManifestError::Empty => write!(f, "Zero sized message"),
+            ManifestError::TooLarge { limit, size } => write!(
+                f,
+                "Manifest is {} bytes, more than the {} bytes allowed",
+                size, limit
+            ),
+            ManifestError::Truncated { expected, received } => write!(
+                f,
+                "Manifest truncated: expected {} bytes, got {}",
+                expected, received
+            ),
+            ManifestError::Overlong { expected } => write!(
+                f,
+                "Manifest is longer than the {} bytes announced",
+                expected
+            ),
+            ManifestError::Signature(e) => e.fmt(f),
+            ManifestError::Json(e) => write!(f, "Malformed manifest: {}", e),
+        }
+    }
+}
+
+/// An update refused by the rules of what this device may install.
+#[derive(Debug)]
+pub enum PolicyError {
+    /// The image is not an app image for this project and chip.
+    Image(ImageError),
+    /// The manifest is older than one already accepted, likely replayed.
+    Replayed { sequence: u64, highest: u64 },
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyError::Image(e) => e.fmt(f),
+            PolicyError::Replayed { sequence, highest } => write!(
+                f,
+                "Manifest sequence {} is older than the accepted {}",
+                sequence, highest
+            ),
+        }
+    }
+}
+
+/// Why a manifest's validity period could not be confirmed, times in seconds
+/// since the Unix epoch.
+#[derive(Debug)]
+pub enum StaleError {
+    /// The clock has not been synchronized yet.
+    ClockNotSet,
+    Expired {
+        expires_at: u64,
+        now: u64,
+    },
+    /// Issued further in the future than clock skew explains.
+    NotYetValid {
+        issued_at: u64,
+        now: u64,
+    },
+}
+
+impl fmt::Display for StaleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StaleError::ClockNotSet => write!(f, "clock not set, cannot check expiry"),
+            StaleError::Expired { expires_at, now } => {
+                write!(f, "manifest expired at {}, it is {}", expires_at, now)
+            }
+            StaleError::NotYetValid { issued_at, now } => write!(
+                f,
+                "manifest issued at {}, in the future of {}",
+                issued_at, now
+            ),
+        }
+    }
+}
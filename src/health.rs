@@ -0,0 +1,224 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_sys::{
+    esp, esp_ota_get_running_partition, esp_ota_get_state_partition, esp_ota_img_states_t,
+    esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY, esp_ota_mark_app_invalid_rollback_and_reboot,
+    esp_ota_mark_app_valid_cancel_rollback, esp_restart,
+};
+use esp_ota_template::blocklist::{BlockedImage, Blocklist};
+use log::{error, info, warn};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const NAMESPACE: &str = "health";
+const ROLLBACK_REASON: &str = "rollback_why";
+pub const REASON_MAX_LEN: usize = 127;
+/// The image last written to the inactive slot, as `version sha256`. Kept
+/// once it is confirmed, the supervisor may still roll it back.
+const INSTALLED: &str = "installed";
+const BLOCKLIST: &str = "blocklist";
+const BLOCKLIST_MAX_LEN: usize = 511;
+
+/// Guards the first boot of a freshly flashed image.
+///
+/// While the running image is pending verification, a failed check or a
+/// missed deadline records the reason in NVS and reboots into the previous
+/// slot. Once every check has passed, `confirm` marks the image valid.
+pub struct BootVerifier {
+    nvs: EspDefaultNvsPartition,
+    pending: bool,
+    confirmed: Arc<AtomicBool>,
+    rolled_back: Option<String>,
+    started: Instant,
+    deadline: Duration,
+}
+
+impl BootVerifier {
+    pub fn start(nvs: EspDefaultNvsPartition, deadline: Duration) -> Result<BootVerifier> {
+        let started = Instant::now();
+        let rolled_back = take_rollback_reason(&nvs)?;
+        if let Some(reason) = &rolled_back {
+            warn!("Previous image was rolled back: {}", reason);
+        }
+
+        let pending = pending_verify();
+        if !pending {
+            // The bootloader goes back on its own when a new image resets
+            // before it could be confirmed or rolled back.
+            if let Some(image) = installed(&nvs)? {
+                if image.version != crate::version::running().to_string() {
+                    warn!("Image {} never booted through", image.version);
+                    block_installed(&nvs)?;
+                }
+            }
+        }
+        let confirmed = Arc::new(AtomicBool::new(false));
+
+        if pending {
+            info!("Image pending verification, deadline {:?}", deadline);
+            let nvs = nvs.clone();
+            let confirmed = confirmed.clone();
+            thread::spawn(move || {
+                thread::sleep(deadline);
+                if !confirmed.load(Ordering::SeqCst) {
+                    rollback(
+                        &nvs,
+                        &format!("Health check not passed within {:?}", deadline),
+                    );
+                }
+            });
+        }
+
+        Ok(BootVerifier {
+            nvs,
+            pending,
+            confirmed,
+            rolled_back,
+            started,
+            deadline,
+        })
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Time left to pass every check before a pending image is rolled back.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.saturating_sub(self.started.elapsed())
+    }
+
+    /// Why the image booted before this one was rolled back, if it was.
+    pub fn rolled_back(&self) -> Option<&str> {
+        self.rolled_back.as_deref()
+    }
+
+    /// Passes `result` through, rolling back instead while the image is pending verification.
+    pub fn check<T>(&self, what: &str, result: Result<T>) -> Result<T> {
+        match result {
+            Err(e) if self.pending => rollback(&self.nvs, &format!("{} check failed: {}", what, e)),
+            result => result,
+        }
+    }
+
+    pub fn confirm(&self) -> Result<()> {
+        if self.pending && !self.confirmed.swap(true, Ordering::SeqCst) {
+            esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() })?;
+            info!("Image marked valid");
+        }
+        Ok(())
+    }
+}
+
+fn pending_verify() -> bool {
+    let mut state: esp_ota_img_states_t = 0;
+    // Not supported when running from a factory or test partition.
+    let supported =
+        esp!(unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) })
+            .is_ok();
+    supported && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
+
+/// Marks the running image invalid and reboots into the previous one, leaving
+/// `reason` for it to find and the image blocked from being installed again.
+pub fn rollback(nvs: &EspDefaultNvsPartition, reason: &str) -> ! {
+    error!("Rolling back: {}", reason);
+    if let Err(e) = record_rollback_reason(nvs, reason) {
+        error!("Could not record rollback reason: {}", e);
+    }
+    if let Err(e) = block_running(nvs) {
+        error!("Could not block the rolled back image: {}", e);
+    }
+    unsafe {
+        esp_ota_mark_app_invalid_rollback_and_reboot();
+        // Only reached if there is no previous image to go back to.
+        esp_restart()
+    }
+}
+
+/// Cuts `reason` down to what fits an NVS string of `REASON_MAX_LEN`.
+pub fn truncate(reason: &str) -> &str {
+    let mut end = reason.len().min(REASON_MAX_LEN);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    &reason[..end]
+}
+
+fn record_rollback_reason(nvs: &EspDefaultNvsPartition, reason: &str) -> Result<()> {
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    nvs.set_str(ROLLBACK_REASON, truncate(reason))?;
+    Ok(())
+}
+
+fn take_rollback_reason(nvs: &EspDefaultNvsPartition) -> Result<Option<String>> {
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let mut buf = [0_u8; REASON_MAX_LEN + 1];
+    let reason = nvs.get_str(ROLLBACK_REASON, &mut buf)?.map(str::to_owned);
+    if reason.is_some() {
+        nvs.remove(ROLLBACK_REASON)?;
+    }
+    Ok(reason)
+}
+
+/// Remembers the image just written to the inactive slot, so it can be
+/// blocked should it be rolled back.
+pub fn record_installed(nvs: &EspDefaultNvsPartition, version: &str, sha256: &str) -> Result<()> {
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    nvs.set_str(INSTALLED, &format!("{} {}", version, sha256))?;
+    Ok(())
+}
+
+/// Images rolled back on this device, not to be installed again.
+pub fn blocklist(nvs: &EspDefaultNvsPartition) -> Result<Blocklist> {
+    let nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let mut buf = [0_u8; BLOCKLIST_MAX_LEN + 1];
+    Ok(nvs
+        .get_str(BLOCKLIST, &mut buf)?
+        .map(Blocklist::parse)
+        .unwrap_or_default())
+}
+
+fn installed(nvs: &EspDefaultNvsPartition) -> Result<Option<BlockedImage>> {
+    let nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let mut buf = [0_u8; 128];
+    let Some(installed) = nvs.get_str(INSTALLED, &mut buf)? else {
+        return Ok(None);
+    };
+    Ok(installed
+        .split_once(' ')
+        .map(|(version, sha256)| BlockedImage {
+            version: version.to_owned(),
+            sha256: sha256.to_owned(),
+        }))
+}
+
+/// Blocks the running image, when it is the one the updater installed.
+/// Otherwise that is an update written since, not the image that failed.
+fn block_running(nvs: &EspDefaultNvsPartition) -> Result<()> {
+    match installed(nvs)? {
+        Some(image) if image.version == crate::version::running().to_string() => {
+            block_installed(nvs)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Moves the installed image to the blocklist.
+fn block_installed(nvs: &EspDefaultNvsPartition) -> Result<()> {
+    let Some(image) = installed(nvs)? else {
+        return Ok(());
+    };
+    let mut blocklist = blocklist(nvs)?;
+    blocklist.block(image);
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    nvs.set_str(BLOCKLIST, &blocklist.to_string())?;
+    nvs.remove(INSTALLED)?;
+    Ok(())
+}
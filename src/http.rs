@@ -0,0 +1,43 @@
+use anyhow::Result;
+use embedded_svc::http::Method;
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_ota_template::transport::{Body, Response, Transport};
+
+/// HTTPS through the ESP-IDF client, trusting the global CA store and the
+/// certificate bundle.
+pub struct EspTransport;
+
+impl Transport for EspTransport {
+    type Body = EspBody;
+
+    fn get(&mut self, url: &str, range_start: u64) -> Result<Response<EspBody>> {
+        let mut connection = EspHttpConnection::new(&Configuration {
+            use_global_ca_store: true,
+            crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+
+        let range = format!("bytes={}-", range_start);
+        let headers = [("Range", range.as_str())];
+        let headers: &[_] = if range_start > 0 { &headers } else { &[] };
+        connection.initiate_request(Method::Get, url, headers)?;
+        connection.initiate_response()?;
+
+        Ok(Response {
+            status: connection.status(),
+            content_length: connection
+                .header("Content-Length")
+                .and_then(|len| len.trim().parse().ok()),
+            content_range: connection.header("Content-Range").map(str::to_owned),
+            body: EspBody(connection),
+        })
+    }
+}
+
+pub struct EspBody(EspHttpConnection);
+
+impl Body for EspBody {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        Ok(self.0.read(buf)?)
+    }
+}
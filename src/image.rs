@@ -0,0 +1,249 @@
+use core::fmt;
+use semver::Version;
+
+/// Offset of `chip_id` in `esp_image_header_t`.
+const CHIP_ID_OFFSET: usize = 12;
+/// `esp_image_header_t` followed by the first `esp_image_segment_header_t`.
+const APP_DESC_OFFSET: usize = 24 + 8;
+/// Size of `esp_app_desc_t`.
+const APP_DESC_LEN: usize = 256;
+/// Bytes needed at the start of a stream to validate it as an app image.
+pub const HEADER_LEN: usize = APP_DESC_OFFSET + APP_DESC_LEN;
+
+const IMAGE_MAGIC: u8 = 0xE9;
+const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+
+#[derive(Debug)]
+pub enum ImageError {
+    TooShort {
+        len: usize,
+    },
+    BadMagic(u8),
+    BadAppDescMagic(u32),
+    TooLarge {
+        size: u64,
+        partition: String,
+        capacity: u64,
+    },
+    ProjectMismatch {
+        expected: String,
+        found: String,
+    },
+    ChipMismatch {
+        expected: u16,
+        found: u16,
+    },
+    VersionMismatch {
+        expected: Version,
+        found: String,
+    },
+    /// Below the secure version burnt in eFuse, the bootloader would refuse it.
+    SecureVersion {
+        min: u32,
+        found: u32,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::TooShort { len } => {
+                write!(f, "Image is {} bytes, too short to hold an app header", len)
+            }
+            ImageError::BadMagic(magic) => write!(
+                f,
+                "Not an ESP app image: magic byte is {:#04x}, expected {:#04x}",
+                magic, IMAGE_MAGIC
+            ),
+            ImageError::BadAppDescMagic(magic) => write!(
+                f,
+                "Image has no app descriptor: magic word is {:#010x}, expected {:#010x}",
+                magic, APP_DESC_MAGIC
+            ),
+            ImageError::TooLarge {
+                size,
+                partition,
+                capacity,
+            } => write!(
+                f,
+                "Image is {} bytes, partition {} only holds {}",
+                size, partition, capacity
+            ),
+            ImageError::ProjectMismatch { expected, found } => write!(
+                f,
+                "Image is for project {:?}, expected {:?}",
+                found, expected
+            ),
+            ImageError::ChipMismatch { expected, found } => {
+                write!(f, "Image is for chip id {}, expected {}", found, expected)
+            }
+            ImageError::VersionMismatch { expected, found } => write!(
+                f,
+                "Image embeds version {:?}, manifest announced {}",
+                found, expected
+            ),
+            ImageError::SecureVersion { min, found } => write!(
+                f,
+                "Image has secure version {}, the device requires at least {}",
+                found, min
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+/// What an image says about itself in its header and `esp_app_desc_t`.
+#[derive(Debug)]
+pub struct ImageInfo {
+    pub chip_id: u16,
+    pub secure_version: u32,
+    pub version: String,
+    pub project_name: String,
+    pub idf_version: String,
+}
+
+/// Parses the first `HEADER_LEN` bytes of a download, checking they look like an app image.
+pub fn parse_header(head: &[u8]) -> Result<ImageInfo, ImageError> {
+    if head.len() < HEADER_LEN {
+        return Err(ImageError::TooShort { len: head.len() });
+    }
+    if head[0] != IMAGE_MAGIC {
+        return Err(ImageError::BadMagic(head[0]));
+    }
+    let desc = &head[APP_DESC_OFFSET..];
+    let magic = u32::from_le_bytes([desc[0], desc[1], desc[2], desc[3]]);
+    if magic != APP_DESC_MAGIC {
+        return Err(ImageError::BadAppDescMagic(magic));
+    }
+
+    Ok(ImageInfo {
+        chip_id: u16::from_le_bytes([head[CHIP_ID_OFFSET], head[CHIP_ID_OFFSET + 1]]),
+        secure_version: u32::from_le_bytes([desc[4], desc[5], desc[6], desc[7]]),
+        version: c_string(&desc[16..48]),
+        project_name: c_string(&desc[48..80]),
+        idf_version: c_string(&desc[112..144]),
+    })
+}
+
+/// Refuses images built for another project or chip, whose embedded version
+/// is not the one the manifest announced, or that the bootloader would not
+/// boot because of anti-rollback.
+pub fn check_app(
+    info: &ImageInfo,
+    project_name: &str,
+    chip_id: u16,
+    version: &Version,
+    min_secure_version: u32,
+) -> Result<(), ImageError> {
+    if info.project_name != project_name {
+        return Err(ImageError::ProjectMismatch {
+            expected: project_name.to_owned(),
+            found: info.project_name.clone(),
+        });
+    }
+    if info.chip_id != chip_id {
+        return Err(ImageError::ChipMismatch {
+            expected: chip_id,
+            found: info.chip_id,
+        });
+    }
+    if Version::parse(&info.version).ok().as_ref() != Some(version) {
+        return Err(ImageError::VersionMismatch {
+            expected: version.clone(),
+            found: info.version.clone(),
+        });
+    }
+    if info.secure_version < min_secure_version {
+        return Err(ImageError::SecureVersion {
+            min: min_secure_version,
+            found: info.secure_version,
+        });
+    }
+    Ok(())
+}
+
+fn c_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROJECT: &str = "esp-ota-template";
+
+    /// The header of an image for `PROJECT` on chip 0 with `version` and `secure_version`.
+    fn header(version: &str, secure_version: u32) -> Vec<u8> {
+        let mut head = vec![0_u8; HEADER_LEN];
+        head[0] = IMAGE_MAGIC;
+        let desc = &mut head[APP_DESC_OFFSET..];
+        desc[..4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        desc[4..8].copy_from_slice(&secure_version.to_le_bytes());
+        desc[16..16 + version.len()].copy_from_slice(version.as_bytes());
+        desc[48..48 + PROJECT.len()].copy_from_slice(PROJECT.as_bytes());
+        head
+    }
+
+    fn check(head: &[u8], version: &str, min_secure_version: u32) -> Result<(), ImageError> {
+        let info = parse_header(head).unwrap();
+        let version = Version::parse(version).unwrap();
+        check_app(&info, PROJECT, 0, &version, min_secure_version)
+    }
+
+    #[test]
+    fn matching_image_passes() {
+        let info = parse_header(&header("0.2.0", 1)).unwrap();
+        assert_eq!(info.version, "0.2.0");
+        assert_eq!(info.project_name, PROJECT);
+        assert_eq!(info.secure_version, 1);
+        check(&header("0.2.0", 1), "0.2.0", 1).unwrap();
+    }
+
+    #[test]
+    fn short_header_is_refused() {
+        let e = parse_header(&header("0.2.0", 0)[..HEADER_LEN - 1]).unwrap_err();
+        assert!(matches!(e, ImageError::TooShort { len } if len == HEADER_LEN - 1));
+    }
+
+    #[test]
+    fn other_project_is_refused() {
+        let mut head = header("0.2.0", 0);
+        head[APP_DESC_OFFSET + 48..APP_DESC_OFFSET + 52].copy_from_slice(b"blnk");
+        let e = check(&head, "0.2.0", 0).unwrap_err();
+        assert!(
+            matches!(e, ImageError::ProjectMismatch { found, .. } if found.starts_with("blnk"))
+        );
+    }
+
+    #[test]
+    fn other_chip_is_refused() {
+        let mut head = header("0.2.0", 0);
+        head[CHIP_ID_OFFSET..CHIP_ID_OFFSET + 2].copy_from_slice(&9_u16.to_le_bytes());
+        let e = check(&head, "0.2.0", 0).unwrap_err();
+        assert!(matches!(
+            e,
+            ImageError::ChipMismatch {
+                expected: 0,
+                found: 9
+            }
+        ));
+    }
+
+    #[test]
+    fn other_version_is_refused() {
+        let e = check(&header("0.2.1", 0), "0.2.0", 0).unwrap_err();
+        assert!(matches!(e, ImageError::VersionMismatch { found, .. } if found == "0.2.1"));
+
+        // An IDF style version, as `git describe` makes it, is not semver.
+        let e = check(&header("v0.2.0-dirty", 0), "0.2.0", 0).unwrap_err();
+        assert!(matches!(e, ImageError::VersionMismatch { found, .. } if found == "v0.2.0-dirty"));
+    }
+
+    #[test]
+    fn secure_version_below_efuse_is_refused() {
+        let e = check(&header("0.2.0", 1), "0.2.0", 2).unwrap_err();
+        assert!(matches!(e, ImageError::SecureVersion { min: 2, found: 1 }));
+    }
+}
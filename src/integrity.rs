@@ -0,0 +1,94 @@
+use core::fmt;
+use sha2::{Digest, Sha256};
+
+#[derive(Debug)]
+pub enum IntegrityError {
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// The server is about to send a different number of bytes than the manifest pins.
+    ContentLengthMismatch {
+        expected: u64,
+        content_length: u64,
+    },
+    DigestMismatch {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::SizeMismatch { expected, actual } => write!(
+                f,
+                "Image size mismatch: expected {} bytes, got {}",
+                expected, actual
+            ),
+            IntegrityError::ContentLengthMismatch {
+                expected,
+                content_length,
+            } => write!(
+                f,
+                "Content-Length is {} bytes, expected {}",
+                content_length, expected
+            ),
+            IntegrityError::DigestMismatch { expected, actual } => write!(
+                f,
+                "Image SHA-256 mismatch: expected {}, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// Hashes and counts an image as it is streamed in.
+pub struct ImageVerifier {
+    hasher: Sha256,
+    written: u64,
+}
+
+impl Default for ImageVerifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageVerifier {
+    pub fn new() -> ImageVerifier {
+        ImageVerifier {
+            hasher: Sha256::new(),
+            written: 0,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.written += data.len() as u64;
+    }
+
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Checks the streamed bytes against the size and hex digest from the manifest.
+    pub fn verify(self, size: u64, sha256: &str) -> Result<(), IntegrityError> {
+        if self.written != size {
+            return Err(IntegrityError::SizeMismatch {
+                expected: size,
+                actual: self.written,
+            });
+        }
+        let actual = hex::encode(self.hasher.finalize());
+        if !actual.eq_ignore_ascii_case(sha256.trim()) {
+            return Err(IntegrityError::DigestMismatch {
+                expected: sha256.to_owned(),
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,18 @@
+//! Update logic that does not depend on ESP-IDF, so it can be built and
+//! exercised on the host with the in-memory transport, sink and store.
+
+pub mod app;
+pub mod blocklist;
+pub mod channel;
+pub mod error;
+pub mod image;
+pub mod integrity;
+pub mod progress;
+pub mod reboot;
+pub mod rollout;
+pub mod sequence;
+pub mod signature;
+pub mod sink;
+pub mod state;
+pub mod transport;
+pub mod updater;
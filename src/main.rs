@@ -1,47 +1,45 @@
-use anyhow::{bail, Ok, Result};
-use core::str;
-use embedded_svc::{http::client::Client, io::Read};
+use anyhow::Result;
 use esp_idf_hal::prelude::Peripherals;
-use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
-    http::client::{Configuration, EspHttpConnection},
-};
+use esp_idf_svc::{eventloop::EspSystemEventLoop, nvs::EspDefaultNvsPartition, sntp::EspSntp};
 
-use log::info;
-use std::{thread, time::Duration};
+use log::{error, info};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 mod wifi;
 use wifi::wifi;
 // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 use esp_idf_sys as _;
 
-use serde::{Deserialize, Serialize};
-
-use semver::Version;
-
-use crate::run::run;
-
+mod clock;
+mod daemon;
+mod device;
+mod health;
+mod http;
+mod nvs_progress;
+mod nvs_sequence;
 mod run;
+mod settings;
+mod slot;
+mod supervisor;
+mod version;
+
+use esp_ota_template::{
+    app::{Application, Context},
+    reboot::RebootHandshake,
+    state::{StateHandle, UpdateState},
+    updater::{Updater, UpdaterConfig},
+};
+use health::BootVerifier;
+use http::EspTransport;
+use nvs_sequence::NvsSequenceStore;
+use settings::SettingsStore;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct UpdateJson {
-    version: String,
-    link: String,
-}
-
-#[derive(Debug)]
-struct Update {
-    version: Version,
-    link: String,
-}
-
-impl Update {
-    pub fn new(json: UpdateJson) -> Update {
-        let version = Version::parse(&json.version).unwrap();
-        let link = json.link;
-        Update { version, link }
-    }
-}
+/// What SNTP leaves of the health check deadline, for fetching the manifest
+/// and the application's own check.
+const CLOCK_WAIT_MARGIN: Duration = Duration::from_secs(30);
 
 #[toml_cfg::toml_config]
 pub struct Config {
@@ -49,6 +47,47 @@ pub struct Config {
     wifi_ssid: &'static str,
     #[default("")]
     wifi_psk: &'static str,
+    // Hex encoded Ed25519 key manifests are signed with, `examples/manifest.rs`
+    // makes one and signs with it.
+    #[default("")]
+    ota_public_key: &'static str,
+    #[default(120)]
+    health_check_timeout_secs: u64,
+    // Firmware from before signed manifests polls `update.json` instead, which
+    // is kept in its old format to bring it over to this one.
+    #[default("https://raw.githubusercontent.com/Mirkopoj/ESP-OTA-Template/master/manifest.json")]
+    manifest_url: &'static str,
+    // Comma separated, tried in turn when `manifest_url` fails.
+    #[default("")]
+    fallback_manifest_urls: &'static str,
+    #[default(30)]
+    poll_interval_secs: u64,
+    #[default(10)]
+    poll_jitter_secs: u64,
+    #[default(true)]
+    updates_enabled: bool,
+    #[default("stable")]
+    channel: &'static str,
+    #[default("")]
+    board: &'static str,
+    #[default(4096)]
+    manifest_max_size: u64,
+    #[default(10)]
+    reboot_grace_secs: u64,
+    #[default(120)]
+    reboot_max_defer_secs: u64,
+    #[default(3)]
+    app_max_restarts: u32,
+    #[default(2)]
+    app_restart_backoff_secs: u64,
+    #[default(1)]
+    app_max_crash_reboots: u8,
+    #[default(300)]
+    app_stable_secs: u64,
+    // How long the updater gets to install a fix for an application whose
+    // init failed, before the chip reboots or the image is rolled back.
+    #[default(300)]
+    app_init_grace_secs: u64,
 }
 
 fn main() -> Result<()> {
@@ -57,115 +96,91 @@ fn main() -> Result<()> {
 
     let peripherals = Peripherals::take().unwrap();
     let sysloop = EspSystemEventLoop::take()?;
+    let nvs = EspDefaultNvsPartition::take()?;
 
     // The constant `CONFIG` is auto-generated by `toml_config`.
     let app_config = CONFIG;
 
-    // Connect to the Wi-Fi network
-    let _wifi = wifi(
-        app_config.wifi_ssid,
-        app_config.wifi_psk,
-        peripherals.modem,
-        sysloop,
+    let boot = BootVerifier::start(
+        nvs.clone(),
+        Duration::from_secs(app_config.health_check_timeout_secs),
     )?;
 
-    let run_thread = thread::spawn(move || run());
-
-    let link = ota()?;
-    
-    ota_update(link)?;
-
-    let _ = run_thread.join();
-
-    Ok(())
-}
-
-fn ota() -> Result<String> {
-    let update = check_update(
-        "https://raw.githubusercontent.com/Mirkopoj/ESP-OTA-Template/master/update.json",
+    // Connect to the Wi-Fi network
+    let esp_wifi = boot.check(
+        "Wi-Fi",
+        wifi(
+            app_config.wifi_ssid,
+            app_config.wifi_psk,
+            peripherals.modem,
+            sysloop,
+        ),
     )?;
-
-    let version = update.version;
-
-    loop {
-        thread::sleep(Duration::from_secs(30));
-        let update = check_update(
-            "https://raw.githubusercontent.com/Mirkopoj/ESP-OTA-Template/master/update.json",
+    // Keeps the clock set, manifests are only accepted within their validity period.
+    let _sntp = EspSntp::new_default()?;
+
+    let mut app = run::app();
+    // Past verification the supervisor deals with a failed init, the updater
+    // still has to run to fetch a fix.
+    let init = boot.check("Application init", app.init());
+    let app: Arc<dyn Application> = Arc::new(app);
+
+    let state = StateHandle::new();
+    if let Some(reason) = boot.rolled_back() {
+        app.on_rollback(reason);
+        state.set(UpdateState::RolledBack {
+            reason: reason.to_owned(),
+        });
+    }
+    let ctx = Context {
+        state,
+        reboot: RebootHandshake::new(),
+        settings: Arc::new(Mutex::new(SettingsStore::new(nvs.clone())?)),
+    };
+    let supervisor = supervisor::spawn(app.clone(), ctx.clone(), nvs.clone(), init)?;
+
+    if boot.is_pending() {
+        boot.check("Wi-Fi", wifi::connected(&esp_wifi))?;
+        let settings = SettingsStore::new(nvs.clone())?.load()?;
+        boot.check(
+            "Manifest",
+            new_updater(StateHandle::new())
+                .fetch_manifest(
+                    &settings.manifest_urls(),
+                    &mut NvsSequenceStore::new(nvs.clone())?,
+                    clock::wait_set(boot.remaining().saturating_sub(CLOCK_WAIT_MARGIN)),
+                )
+                .map_err(anyhow::Error::from),
         )?;
-        println!("Version actual: {}", version);
-        println!("Version leida: {}", update.version);
-        if update.version > version {
-            break;
-        }
+        boot.check("Application", app.health_check())?;
     }
+    boot.confirm()?;
 
-    Ok(update.link)
-}
-
-fn connect() -> Result<Client<EspHttpConnection>> {
-    let connection = EspHttpConnection::new(&Configuration {
-        use_global_ca_store: true,
-        crt_bundle_attach: Some(esp_idf_sys::esp_crt_bundle_attach),
-        ..Default::default()
-    })?;
-    let client = Client::wrap(connection);
+    let version = version::running();
+    info!("Running firmware version {}", version);
+    if boot.is_pending() {
+        app.after_update(&version);
+    }
 
-    Ok(client)
-}
+    daemon::spawn(version, nvs, ctx, app);
 
-fn check_update(url: impl AsRef<str>) -> Result<Update> {
-    let mut client = connect()?;
-    let request = client.get(url.as_ref())?;
-    let response = request.submit()?;
-    let status = response.status();
-
-    let update: Update;
-
-    match status {
-        200..=299 => {
-            let mut buf = [0_u8; 256];
-            let mut reader = response;
-            let size = Read::read(&mut reader, &mut buf)?;
-            if size == 0 {
-                bail!("Zero sized message");
-            }
-            update = Update::new(serde_json::from_slice(&buf[..size])?);
-        }
-        _ => bail!("Unexpected response code: {}", status),
+    if supervisor.join().is_err() {
+        error!("Supervisor panicked");
     }
 
-    Ok(update)
+    Ok(())
 }
 
-fn ota_update(url: impl AsRef<str>) -> Result<()> {
-    let mut client = connect()?;
-    let request = client.get(url.as_ref())?;
-    let response = request.submit()?;
-    let status = response.status();
-    let mut ota = esp_ota::OtaUpdate::begin()?;
-
-    info!("Begin OTA");
-
-    match status {
-        200..=299 => {
-            let mut buf = [0_u8; 256];
-            let mut reader = response;
-            loop {
-                let size = Read::read(&mut reader, &mut buf)?;
-                info!("Read {} bytes", size);
-                if size == 0 {
-                    break;
-                }
-                ota.write(&buf)?;
-                info!("Wrote {} bytes", size);
-            }
-        }
-
-        _ => bail!("Unexpected response code: {}", status),
-    }
-
-    let mut completed_ota = ota.finalize()?;
-    completed_ota.set_as_boot_partition()?;
-    info!("OTA Complete");
-    completed_ota.restart();
+fn new_updater(state: StateHandle) -> Updater<EspTransport> {
+    Updater::new(
+        EspTransport,
+        UpdaterConfig {
+            public_key: CONFIG.ota_public_key.to_owned(),
+            manifest_max_size: CONFIG.manifest_max_size,
+            project_name: version::project_name(),
+            chip_id: esp_idf_sys::CONFIG_IDF_FIRMWARE_CHIP_ID as u16,
+            min_secure_version: version::min_secure_version(),
+        },
+        state,
+    )
 }
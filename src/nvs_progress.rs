@@ -0,0 +1,58 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_ota_template::progress::{Progress, ProgressStore};
+
+const NAMESPACE: &str = "ota";
+const VERSION: &str = "dl_version";
+const SHA256: &str = "dl_sha256";
+const WRITTEN: &str = "dl_written";
+
+/// Keeps the download progress in NVS so it survives a reboot.
+pub struct NvsProgressStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl NvsProgressStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<NvsProgressStore> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        Ok(NvsProgressStore { nvs })
+    }
+}
+
+impl ProgressStore for NvsProgressStore {
+    fn load(&self) -> Result<Option<Progress>> {
+        let mut version = [0_u8; 64];
+        let mut sha256 = [0_u8; 65];
+        let version = self.nvs.get_str(VERSION, &mut version)?;
+        let sha256 = self.nvs.get_str(SHA256, &mut sha256)?;
+        let written = self.nvs.get_u64(WRITTEN)?;
+
+        let progress = match (version, sha256, written) {
+            (Some(version), Some(sha256), Some(written)) => Some(Progress {
+                version: version.to_owned(),
+                sha256: sha256.to_owned(),
+                written,
+            }),
+            _ => None,
+        };
+        Ok(progress)
+    }
+
+    fn start(&mut self, version: &str, sha256: &str, written: u64) -> Result<()> {
+        self.nvs.set_str(VERSION, version)?;
+        self.nvs.set_str(SHA256, sha256)?;
+        self.set_written(written)
+    }
+
+    fn set_written(&mut self, written: u64) -> Result<()> {
+        self.nvs.set_u64(WRITTEN, written)?;
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.nvs.remove(VERSION)?;
+        self.nvs.remove(SHA256)?;
+        self.nvs.remove(WRITTEN)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,29 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_ota_template::sequence::SequenceStore;
+
+const NAMESPACE: &str = "ota";
+const SEQUENCE: &str = "manifest_seq";
+
+/// Keeps the highest accepted manifest sequence in NVS, across updates too.
+pub struct NvsSequenceStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl NvsSequenceStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<NvsSequenceStore> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        Ok(NvsSequenceStore { nvs })
+    }
+}
+
+impl SequenceStore for NvsSequenceStore {
+    fn highest(&self) -> Result<u64> {
+        Ok(self.nvs.get_u64(SEQUENCE)?.unwrap_or(0))
+    }
+
+    fn accept(&mut self, sequence: u64) -> Result<()> {
+        self.nvs.set_u64(SEQUENCE, sequence)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,59 @@
+use anyhow::Result;
+
+/// How far the download of an image into the inactive slot got.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    pub version: String,
+    pub sha256: String,
+    pub written: u64,
+}
+
+/// Keeps the download progress across attempts and, on target, reboots.
+pub trait ProgressStore {
+    fn load(&self) -> Result<Option<Progress>>;
+
+    fn start(&mut self, version: &str, sha256: &str, written: u64) -> Result<()>;
+
+    fn set_written(&mut self, written: u64) -> Result<()>;
+
+    fn clear(&mut self) -> Result<()>;
+}
+
+/// Progress that lasts only as long as the store itself.
+#[derive(Default)]
+pub struct MemProgressStore {
+    progress: Option<Progress>,
+}
+
+impl MemProgressStore {
+    pub fn new() -> MemProgressStore {
+        MemProgressStore::default()
+    }
+}
+
+impl ProgressStore for MemProgressStore {
+    fn load(&self) -> Result<Option<Progress>> {
+        Ok(self.progress.clone())
+    }
+
+    fn start(&mut self, version: &str, sha256: &str, written: u64) -> Result<()> {
+        self.progress = Some(Progress {
+            version: version.to_owned(),
+            sha256: sha256.to_owned(),
+            written,
+        });
+        Ok(())
+    }
+
+    fn set_written(&mut self, written: u64) -> Result<()> {
+        if let Some(progress) = &mut self.progress {
+            progress.written = written;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.progress = None;
+        Ok(())
+    }
+}
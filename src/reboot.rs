@@ -0,0 +1,84 @@
+use log::{info, warn};
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Default)]
+struct Handshake {
+    pending: bool,
+    acknowledged: bool,
+    /// Extra time the application asked for, on top of the grace period.
+    deferred: Duration,
+}
+
+/// Lets the updater warn the application before restarting into a new image.
+///
+/// The updater calls `request` and waits until the application calls
+/// `acknowledge`, or the grace period, extended by `defer` up to a hard
+/// limit, runs out. The application polls `is_pending` or waits on
+/// `wait_pending`, and flushes whatever it needs to before acknowledging.
+#[derive(Clone, Default)]
+pub struct RebootHandshake(Arc<(Mutex<Handshake>, Condvar)>);
+
+impl RebootHandshake {
+    pub fn new() -> RebootHandshake {
+        RebootHandshake::default()
+    }
+
+    /// Announces the reboot and blocks until the application acknowledges it
+    /// or the time runs out. Returns whether it was acknowledged.
+    pub fn request(&self, grace: Duration, max: Duration) -> bool {
+        let (lock, cvar) = &*self.0;
+        let start = Instant::now();
+        let mut handshake = lock.lock().unwrap();
+        handshake.pending = true;
+        cvar.notify_all();
+        info!(
+            "Reboot pending, waiting up to {:?} for the application",
+            grace
+        );
+
+        loop {
+            if handshake.acknowledged {
+                return true;
+            }
+            let deadline = start + (grace + handshake.deferred).min(max);
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("Application did not acknowledge the reboot in time");
+                return false;
+            }
+            handshake = cvar.wait_timeout(handshake, deadline - now).unwrap().0;
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        self.0 .0.lock().unwrap().pending
+    }
+
+    /// Blocks up to `timeout` for a reboot to be requested, returns whether one was.
+    pub fn wait_pending(&self, timeout: Duration) -> bool {
+        let (lock, cvar) = &*self.0;
+        let handshake = lock.lock().unwrap();
+        let (handshake, _) = cvar
+            .wait_timeout_while(handshake, timeout, |h| !h.pending)
+            .unwrap();
+        handshake.pending
+    }
+
+    /// Asks for `by` more time before the restart, bounded by the limit
+    /// given to `request`.
+    pub fn defer(&self, by: Duration) {
+        let (lock, cvar) = &*self.0;
+        lock.lock().unwrap().deferred += by;
+        cvar.notify_all();
+    }
+
+    /// Tells the updater the application is ready to be restarted.
+    pub fn acknowledge(&self) {
+        let (lock, cvar) = &*self.0;
+        lock.lock().unwrap().acknowledged = true;
+        cvar.notify_all();
+    }
+}
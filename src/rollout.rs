@@ -0,0 +1,14 @@
+use sha2::{Digest, Sha256};
+
+/// Where `device_id` falls, from 0 to 99, in a rollout seeded by `seed`.
+///
+/// The same device always lands in the same bucket for a given seed, so
+/// raising the percentage of a rollout only ever adds devices to it.
+pub fn bucket(seed: &str, device_id: &str) -> u8 {
+    let digest = Sha256::new()
+        .chain_update(seed.as_bytes())
+        .chain_update(device_id.as_bytes())
+        .finalize();
+    let value = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    (value % 100) as u8
+}
@@ -1,31 +1,64 @@
 use anyhow::Result;
-use std::{thread, time::Duration};
 use esp_idf_sys::{
     esp, gpio_config, gpio_config_t, gpio_int_type_t_GPIO_INTR_DISABLE,
     gpio_mode_t_GPIO_MODE_OUTPUT, gpio_set_level,
 };
+use esp_ota_template::{
+    app::{Application, Context},
+    state::UpdateState,
+};
+use std::time::Duration;
+
+/// The application main wires into the updater, replace it with your own.
+pub fn app() -> impl Application {
+    Blink
+}
+
+/// Blinks the LED on GPIO2, at a rate that shows what the updater is doing.
+struct Blink;
 
-pub fn run() -> Result<()> {
+impl Blink {
     const GPIO_NUM: i32 = 2;
+}
 
-    let io_conf = gpio_config_t {
-        pin_bit_mask: 1 << GPIO_NUM,
-        mode: gpio_mode_t_GPIO_MODE_OUTPUT,
-        pull_up_en: false.into(),
-        pull_down_en: false.into(),
-        intr_type: gpio_int_type_t_GPIO_INTR_DISABLE,
-    };
+impl Application for Blink {
+    fn init(&mut self) -> Result<()> {
+        let io_conf = gpio_config_t {
+            pin_bit_mask: 1 << Self::GPIO_NUM,
+            mode: gpio_mode_t_GPIO_MODE_OUTPUT,
+            pull_up_en: false.into(),
+            pull_down_en: false.into(),
+            intr_type: gpio_int_type_t_GPIO_INTR_DISABLE,
+        };
 
-    unsafe {
-        esp!(gpio_config(&io_conf))?;
+        unsafe {
+            esp!(gpio_config(&io_conf))?;
+        }
+        Ok(())
     }
 
-    let mut led = false;
-    loop {
-        unsafe {
-            esp!(gpio_set_level(GPIO_NUM, led.into()))?;
+    fn run(&self, ctx: Context) -> Result<()> {
+        let mut led = false;
+        loop {
+            unsafe {
+                esp!(gpio_set_level(Self::GPIO_NUM, led.into()))?;
+            }
+            led ^= true;
+            // Blink faster while a new image is being downloaded, slower while
+            // the last update attempt failed
+            let period = match ctx.state.get() {
+                UpdateState::Downloading { .. } => 200,
+                UpdateState::Failed { .. } | UpdateState::Stale { .. } => 3000,
+                _ => 1000,
+            };
+            if ctx.reboot.wait_pending(Duration::from_millis(period)) {
+                // Leave the LED off across the restart
+                unsafe {
+                    esp!(gpio_set_level(Self::GPIO_NUM, 0))?;
+                }
+                ctx.reboot.acknowledge();
+                return Ok(());
+            }
         }
-        led ^= true;
-        thread::sleep(Duration::from_millis(1000));
     }
 }
@@ -0,0 +1,32 @@
+use anyhow::Result;
+
+/// Keeps the highest manifest sequence number accepted so far, so an older
+/// manifest, validly signed but replayed, is refused.
+pub trait SequenceStore {
+    fn highest(&self) -> Result<u64>;
+
+    fn accept(&mut self, sequence: u64) -> Result<()>;
+}
+
+/// Highest sequence held in memory, forgotten when the store is dropped.
+#[derive(Default)]
+pub struct MemSequenceStore {
+    highest: u64,
+}
+
+impl MemSequenceStore {
+    pub fn new() -> MemSequenceStore {
+        MemSequenceStore::default()
+    }
+}
+
+impl SequenceStore for MemSequenceStore {
+    fn highest(&self) -> Result<u64> {
+        Ok(self.highest)
+    }
+
+    fn accept(&mut self, sequence: u64) -> Result<()> {
+        self.highest = sequence;
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_ota_template::{app::UpdaterSettings, channel::Channel};
+use semver::Version;
+use std::time::Duration;
+
+use crate::CONFIG;
+
+const NAMESPACE: &str = "config";
+const MANIFEST_URL: &str = "manifest_url";
+const POLL_INTERVAL: &str = "poll_secs";
+const POLL_JITTER: &str = "jitter_secs";
+const ENABLED: &str = "enabled";
+const CHANNEL: &str = "channel";
+const PIN: &str = "pin";
+
+/// Updater settings in effect, the build-time `Config` with NVS overrides applied.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub manifest_url: String,
+    /// Tried when `manifest_url` does not serve a valid manifest, from `Config` only.
+    pub fallback_manifest_urls: Vec<String>,
+    pub poll_interval: Duration,
+    pub poll_jitter: Duration,
+    pub enabled: bool,
+    pub channel: Channel,
+    /// Newest version to update to, set on the device and kept until cleared.
+    pub pin: Option<Version>,
+}
+
+impl Settings {
+    pub fn manifest_urls(&self) -> Vec<String> {
+        let mut urls = vec![self.manifest_url.clone()];
+        urls.extend(self.fallback_manifest_urls.iter().cloned());
+        urls
+    }
+}
+
+/// Runtime overrides of the updater settings, kept in NVS so the same binary
+/// can be pointed at a different server without rebuilding.
+pub struct SettingsStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl SettingsStore {
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<SettingsStore> {
+        let nvs = EspNvs::new(partition, NAMESPACE, true)?;
+        Ok(SettingsStore { nvs })
+    }
+
+    pub fn load(&self) -> Result<Settings> {
+        let mut buf = [0_u8; 256];
+        let manifest_url = self
+            .nvs
+            .get_str(MANIFEST_URL, &mut buf)?
+            .unwrap_or(CONFIG.manifest_url)
+            .to_owned();
+        let poll_interval = self
+            .nvs
+            .get_u64(POLL_INTERVAL)?
+            .unwrap_or(CONFIG.poll_interval_secs);
+        let poll_jitter = self
+            .nvs
+            .get_u64(POLL_JITTER)?
+            .unwrap_or(CONFIG.poll_jitter_secs);
+        let enabled = self
+            .nvs
+            .get_u8(ENABLED)?
+            .map_or(CONFIG.updates_enabled, |v| v != 0);
+        let channel = self
+            .nvs
+            .get_str(CHANNEL, &mut buf)?
+            .unwrap_or(CONFIG.channel)
+            .parse()?;
+        let pin = self
+            .nvs
+            .get_str(PIN, &mut buf)?
+            .map(Version::parse)
+            .transpose()?;
+
+        let fallback_manifest_urls = CONFIG
+            .fallback_manifest_urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_owned)
+            .collect();
+
+        Ok(Settings {
+            manifest_url,
+            fallback_manifest_urls,
+            poll_interval: Duration::from_secs(poll_interval),
+            poll_jitter: Duration::from_secs(poll_jitter),
+            enabled,
+            channel,
+            pin,
+        })
+    }
+}
+
+impl UpdaterSettings for SettingsStore {
+    fn set_manifest_url(&mut self, url: &str) -> Result<()> {
+        self.nvs.set_str(MANIFEST_URL, url)?;
+        Ok(())
+    }
+
+    fn set_poll_interval(&mut self, interval: Duration) -> Result<()> {
+        self.nvs.set_u64(POLL_INTERVAL, interval.as_secs())?;
+        Ok(())
+    }
+
+    fn set_poll_jitter(&mut self, jitter: Duration) -> Result<()> {
+        self.nvs.set_u64(POLL_JITTER, jitter.as_secs())?;
+        Ok(())
+    }
+
+    fn set_enabled(&mut self, enabled: bool) -> Result<()> {
+        self.nvs.set_u8(ENABLED, enabled.into())?;
+        Ok(())
+    }
+
+    fn set_channel(&mut self, channel: Channel) -> Result<()> {
+        self.nvs.set_str(CHANNEL, channel.as_str())?;
+        Ok(())
+    }
+
+    fn set_pin(&mut self, version: &Version) -> Result<()> {
+        self.nvs.set_str(PIN, &version.to_string())?;
+        Ok(())
+    }
+
+    fn clear_pin(&mut self) -> Result<()> {
+        self.nvs.remove(PIN)?;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        for key in [
+            MANIFEST_URL,
+            POLL_INTERVAL,
+            POLL_JITTER,
+            ENABLED,
+            CHANNEL,
+            PIN,
+        ] {
+            self.nvs.remove(key)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use core::fmt;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{ser::SerializeMap, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+const SIGNATURE_FIELD: &str = "signature";
+
+#[derive(Debug)]
+pub enum SignatureError {
+    MissingKey,
+    InvalidKey,
+    Missing,
+    Malformed,
+    Invalid,
+    Manifest(serde_json::Error),
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::MissingKey => write!(f, "No OTA public key configured"),
+            SignatureError::InvalidKey => write!(f, "Configured OTA public key is not valid"),
+            SignatureError::Missing => write!(f, "Manifest is not signed"),
+            SignatureError::Malformed => write!(f, "Manifest signature is malformed"),
+            SignatureError::Invalid => write!(f, "Manifest signature does not verify"),
+            SignatureError::Manifest(e) => write!(f, "Manifest is not valid JSON: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+/// Checks the detached Ed25519 signature of a manifest and returns its fields.
+///
+/// The signature covers every field of the manifest except `signature`
+/// itself, serialized as compact JSON with object keys sorted, e.g.
+/// `json.dumps(m, sort_keys=True, separators=(",", ":"), ensure_ascii=False)`.
+pub fn verify_manifest(body: &[u8], public_key: &str) -> Result<Value, SignatureError> {
+    let key = parse_key(public_key)?;
+
+    let mut manifest: Value = serde_json::from_slice(body).map_err(SignatureError::Manifest)?;
+    let signature = match manifest.as_object_mut().map(|m| m.remove(SIGNATURE_FIELD)) {
+        Some(Some(Value::String(signature))) => signature,
+        Some(Some(_)) => return Err(SignatureError::Malformed),
+        _ => return Err(SignatureError::Missing),
+    };
+    let signature = parse_signature(&signature)?;
+
+    let message = serde_json::to_vec(&Canonical(&manifest)).map_err(SignatureError::Manifest)?;
+    key.verify(&message, &signature)
+        .map_err(|_| SignatureError::Invalid)?;
+
+    Ok(manifest)
+}
+
+/// Signs a manifest the way `verify_manifest` checks it, returning the hex
+/// encoded signature for its `signature` field. A signature already in the
+/// manifest is ignored.
+pub fn sign_manifest(body: &[u8], secret_key: &str) -> Result<String, SignatureError> {
+    let mut bytes = [0_u8; 32];
+    hex::decode_to_slice(secret_key.trim(), &mut bytes).map_err(|_| SignatureError::InvalidKey)?;
+    let key = SigningKey::from_bytes(&bytes);
+
+    let mut manifest: Value = serde_json::from_slice(body).map_err(SignatureError::Manifest)?;
+    if let Some(manifest) = manifest.as_object_mut() {
+        manifest.remove(SIGNATURE_FIELD);
+    }
+    let message = serde_json::to_vec(&Canonical(&manifest)).map_err(SignatureError::Manifest)?;
+    Ok(hex::encode(key.sign(&message).to_bytes()))
+}
+
+fn parse_key(public_key: &str) -> Result<VerifyingKey, SignatureError> {
+    if public_key.is_empty() {
+        return Err(SignatureError::MissingKey);
+    }
+    let mut bytes = [0_u8; 32];
+    hex::decode_to_slice(public_key.trim(), &mut bytes).map_err(|_| SignatureError::InvalidKey)?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| SignatureError::InvalidKey)
+}
+
+fn parse_signature(signature: &str) -> Result<Signature, SignatureError> {
+    let mut bytes = [0_u8; 64];
+    hex::decode_to_slice(signature.trim(), &mut bytes).map_err(|_| SignatureError::Malformed)?;
+    Ok(Signature::from_bytes(&bytes))
+}
+
+/// Serializes a JSON value with object keys in sorted order, whatever map
+/// implementation `serde_json` was built with.
+struct Canonical<'a>(&'a Value);
+
+impl Serialize for Canonical<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Object(map) => {
+                let sorted: BTreeMap<&String, &Value> = map.iter().collect();
+                let mut out = serializer.serialize_map(Some(sorted.len()))?;
+                for (k, v) in sorted {
+                    out.serialize_entry(k, &Canonical(v))?;
+                }
+                out.end()
+            }
+            Value::Array(items) => serializer.collect_seq(items.iter().map(Canonical)),
+            other => other.serialize(serializer),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0707070707070707070707070707070707070707070707070707070707070707";
+
+    fn public_key() -> String {
+        let mut secret = [0_u8; 32];
+        hex::decode_to_slice(SECRET, &mut secret).unwrap();
+        hex::encode(SigningKey::from_bytes(&secret).verifying_key().to_bytes())
+    }
+
+    fn signed(manifest: &str) -> Value {
+        let mut value: Value = serde_json::from_str(manifest).unwrap();
+        let signature = sign_manifest(manifest.as_bytes(), SECRET).unwrap();
+        value[SIGNATURE_FIELD] = Value::String(signature);
+        value
+    }
+
+    #[test]
+    fn signed_manifest_verifies() {
+        let body = signed(r#"{"sequence": 2, "channels": {"stable": []}}"#).to_string();
+        let manifest = verify_manifest(body.as_bytes(), &public_key()).unwrap();
+        assert_eq!(manifest["sequence"], 2);
+        assert!(manifest.get(SIGNATURE_FIELD).is_none());
+    }
+
+    #[test]
+    fn signature_ignores_key_order_and_whitespace() {
+        let signature = sign_manifest(br#"{"b": 1, "a": [1, 2]}"#, SECRET).unwrap();
+        let body = format!(
+            "{{\n\t\"a\" : [ 1, 2 ],\n\t\"signature\" : \"{}\",\n\t\"b\" : 1\n}}",
+            signature
+        );
+        verify_manifest(body.as_bytes(), &public_key()).unwrap();
+    }
+
+    #[test]
+    fn tampered_field_is_invalid() {
+        let mut manifest = signed(r#"{"sequence": 2}"#);
+        manifest["sequence"] = 3.into();
+        let e = verify_manifest(manifest.to_string().as_bytes(), &public_key()).unwrap_err();
+        assert!(matches!(e, SignatureError::Invalid));
+    }
+
+    #[test]
+    fn missing_or_malformed_signature() {
+        let e = verify_manifest(br#"{"sequence": 2}"#, &public_key()).unwrap_err();
+        assert!(matches!(e, SignatureError::Missing));
+
+        let e = verify_manifest(br#"{"sequence": 2, "signature": 5}"#, &public_key()).unwrap_err();
+        assert!(matches!(e, SignatureError::Malformed));
+
+        let body = br#"{"sequence": 2, "signature": "not hex"}"#;
+        let e = verify_manifest(body, &public_key()).unwrap_err();
+        assert!(matches!(e, SignatureError::Malformed));
+    }
+
+    #[test]
+    fn wrong_or_missing_key() {
+        let body = signed(r#"{"sequence": 2}"#).to_string();
+
+        let e = verify_manifest(body.as_bytes(), "").unwrap_err();
+        assert!(matches!(e, SignatureError::MissingKey));
+
+        let e = verify_manifest(body.as_bytes(), "abcd").unwrap_err();
+        assert!(matches!(e, SignatureError::InvalidKey));
+
+        let other = hex::encode(SigningKey::from_bytes(&[9; 32]).verifying_key().to_bytes());
+        let e = verify_manifest(body.as_bytes(), &other).unwrap_err();
+        assert!(matches!(e, SignatureError::Invalid));
+    }
+}
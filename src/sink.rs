@@ -0,0 +1,87 @@
+use anyhow::{bail, Result};
+
+/// Where a downloaded image is written to, the inactive OTA slot on target.
+///
+/// Writes are at absolute offsets so a download can be resumed, and whatever
+/// was written before the offset passed to `seek` must be kept.
+pub trait OtaSink {
+    fn label(&self) -> String;
+
+    fn capacity(&self) -> u64;
+
+    /// Continues writing at `offset`, keeping the bytes already written before it.
+    fn seek(&mut self, offset: u64);
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<()>;
+
+    /// Makes the written image the one booted on the next restart.
+    fn commit(&mut self) -> Result<()>;
+}
+
+/// An OTA slot backed by a `Vec`, where committing only raises a flag.
+pub struct MemSink {
+    data: Vec<u8>,
+    capacity: u64,
+    committed: bool,
+}
+
+impl MemSink {
+    pub fn new(capacity: u64) -> MemSink {
+        MemSink {
+            data: Vec::new(),
+            capacity,
+            committed: false,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn is_committed(&self) -> bool {
+        self.committed
+    }
+}
+
+impl OtaSink for MemSink {
+    fn label(&self) -> String {
+        "mem".to_owned()
+    }
+
+    fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    fn seek(&mut self, offset: u64) {
+        self.data.truncate(offset as usize);
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let start = offset as usize;
+        let Some(src) = self.data.get(start..start + buf.len()) else {
+            bail!("Read past the end of the written data");
+        };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let end = offset + data.len() as u64;
+        if end > self.capacity {
+            bail!("Image does not fit in {} bytes", self.capacity);
+        }
+        let start = offset as usize;
+        if self.data.len() < end as usize {
+            self.data.resize(end as usize, 0xFF);
+        }
+        self.data[start..end as usize].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.committed = true;
+        Ok(())
+    }
+}
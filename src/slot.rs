@@ -0,0 +1,101 @@
+use anyhow::{bail, Result};
+use core::{ffi::CStr, ptr};
+use esp_idf_sys::{
+    esp, esp_ota_get_next_update_partition, esp_ota_set_boot_partition, esp_partition_erase_range,
+    esp_partition_read, esp_partition_t, esp_partition_write,
+};
+use esp_ota_template::sink::OtaSink;
+
+const SECTOR_SIZE: u64 = 4096;
+
+/// The inactive OTA app partition, written in place so an interrupted
+/// download can continue where it stopped.
+///
+/// Sectors are erased lazily right before they are first written, so
+/// whatever was written before the resume offset is kept.
+pub struct OtaSlot {
+    partition: &'static esp_partition_t,
+    erased: u64,
+}
+
+impl OtaSlot {
+    pub fn next() -> Result<OtaSlot> {
+        let partition = unsafe { esp_ota_get_next_update_partition(ptr::null()).as_ref() };
+        let Some(partition) = partition else {
+            bail!("No OTA partition available to update");
+        };
+        Ok(OtaSlot {
+            partition,
+            erased: 0,
+        })
+    }
+}
+
+impl OtaSink for OtaSlot {
+    fn label(&self) -> String {
+        unsafe { CStr::from_ptr(self.partition.label.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn capacity(&self) -> u64 {
+        self.partition.size.into()
+    }
+
+    fn seek(&mut self, offset: u64) {
+        self.erased = align_up(offset);
+    }
+
+    fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        esp!(unsafe {
+            esp_partition_read(
+                self.partition,
+                offset as _,
+                buf.as_mut_ptr().cast(),
+                buf.len() as _,
+            )
+        })?;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        let end = offset + data.len() as u64;
+        if end > self.capacity() {
+            bail!(
+                "Image does not fit in partition {} ({} bytes)",
+                self.label(),
+                self.capacity()
+            );
+        }
+        if end > self.erased {
+            let erase_to = align_up(end).min(self.capacity());
+            esp!(unsafe {
+                esp_partition_erase_range(
+                    self.partition,
+                    self.erased as _,
+                    (erase_to - self.erased) as _,
+                )
+            })?;
+            self.erased = erase_to;
+        }
+        esp!(unsafe {
+            esp_partition_write(
+                self.partition,
+                offset as _,
+                data.as_ptr().cast(),
+                data.len() as _,
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Validates the written image and boots from it on the next restart.
+    fn commit(&mut self) -> Result<()> {
+        esp!(unsafe { esp_ota_set_boot_partition(self.partition) })?;
+        Ok(())
+    }
+}
+
+fn align_up(offset: u64) -> u64 {
+    (offset + SECTOR_SIZE - 1) / SECTOR_SIZE * SECTOR_SIZE
+}
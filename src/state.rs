@@ -0,0 +1,231 @@
+use log::warn;
+use semver::Version;
+
+use crate::error::OtaError;
+use std::sync::{
+    mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+    Arc, Mutex,
+};
+
+/// States a subscriber can fall behind by before it misses some.
+const SUBSCRIBER_QUEUE_LEN: usize = 16;
+
+/// Where the updater is in checking for, fetching and installing an update.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpdateState {
+    Idle,
+    Checking,
+    UpdateAvailable {
+        version: Version,
+    },
+    Downloading {
+        bytes: u64,
+        total: u64,
+    },
+    Verifying,
+    /// The new image is the boot image, the chip restarts into it next.
+    ReadyToReboot,
+    Failed {
+        reason: String,
+    },
+    /// The manifest could not be trusted as current, it expired or the clock
+    /// is not set yet. Nothing is installed until it is.
+    Stale {
+        reason: String,
+    },
+    /// The previous image failed its health check and was rolled back.
+    RolledBack {
+        reason: String,
+    },
+}
+
+impl UpdateState {
+    /// The state an update attempt that failed with `e` ends in.
+    pub fn failed(e: &OtaError) -> UpdateState {
+        let reason = e.to_string();
+        match e {
+            OtaError::Stale(_) => UpdateState::Stale { reason },
+            _ => UpdateState::Failed { reason },
+        }
+    }
+
+    /// Whether the updater may go from `self` to `next`.
+    pub fn allows(&self, next: &UpdateState) -> bool {
+        use UpdateState::*;
+        matches!(
+            (self, next),
+            (_, Failed { .. } | Stale { .. })
+                | (Idle, RolledBack { .. })
+                | (
+                    Idle | Failed { .. } | Stale { .. } | RolledBack { .. },
+                    Checking | Idle
+                )
+                | (Checking | RolledBack { .. }, UpdateAvailable { .. } | Idle)
+                | (UpdateAvailable { .. }, Downloading { .. } | Idle)
+                | (Downloading { .. }, Downloading { .. } | Verifying)
+                // A digest mismatch moves on to the next mirror.
+                | (Verifying, Downloading { .. } | ReadyToReboot)
+        )
+    }
+}
+
+struct Shared {
+    state: UpdateState,
+    subscribers: Vec<SyncSender<UpdateState>>,
+}
+
+impl Shared {
+    fn enter(&mut self, next: UpdateState) {
+        if !self.state.allows(&next) {
+            warn!("Unexpected transition {:?} -> {:?}", self.state, next);
+        }
+        self.state = next.clone();
+        // Never blocks the updater on a subscriber, one that stopped reading
+        // only misses states.
+        self.subscribers
+            .retain(|subscriber| match subscriber.try_send(next.clone()) {
+                Ok(()) | Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+}
+
+/// Shared view of the update state, cheap to clone into other threads.
+#[derive(Clone)]
+pub struct StateHandle(Arc<Mutex<Shared>>);
+
+impl StateHandle {
+    pub fn new() -> StateHandle {
+        StateHandle(Arc::new(Mutex::new(Shared {
+            state: UpdateState::Idle,
+            subscribers: Vec::new(),
+        })))
+    }
+
+    pub fn get(&self) -> UpdateState {
+        self.0.lock().unwrap().state.clone()
+    }
+
+    /// Moves to `next` and tells every subscriber. Transitions the state
+    /// machine does not expect are logged but still applied, the state has to
+    /// reflect what the updater actually does.
+    pub fn set(&self, next: UpdateState) {
+        self.0.lock().unwrap().enter(next);
+    }
+
+    /// Like `set`, but keeps a rollback being reported, so the application
+    /// sees it until an update actually starts.
+    pub fn set_unless_rolled_back(&self, next: UpdateState) {
+        let mut shared = self.0.lock().unwrap();
+        if !matches!(shared.state, UpdateState::RolledBack { .. }) {
+            shared.enter(next);
+        }
+    }
+
+    /// Receives the states entered from now on, until the receiver is dropped.
+    /// States entered while `SUBSCRIBER_QUEUE_LEN` are waiting to be received
+    /// are not queued, `get` still tells the current one.
+    pub fn subscribe(&self) -> Receiver<UpdateState> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_QUEUE_LEN);
+        self.0.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+}
+
+impl Default for StateHandle {
+    fn default() -> Self {
+        StateHandle::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::StaleError;
+
+    fn downloading(bytes: u64) -> UpdateState {
+        UpdateState::Downloading { bytes, total: 100 }
+    }
+
+    #[test]
+    fn update_goes_through_expected_states() {
+        let available = UpdateState::UpdateAvailable {
+            version: Version::new(0, 2, 0),
+        };
+        let path = [
+            UpdateState::Idle,
+            UpdateState::Checking,
+            available,
+            downloading(0),
+            downloading(50),
+            UpdateState::Verifying,
+            // The digest did not match, the next mirror is tried.
+            downloading(0),
+            UpdateState::Verifying,
+            UpdateState::ReadyToReboot,
+        ];
+        for step in path.windows(2) {
+            assert!(step[0].allows(&step[1]), "{:?} -> {:?}", step[0], step[1]);
+        }
+    }
+
+    #[test]
+    fn unexpected_transitions_are_refused() {
+        let failed = UpdateState::Failed {
+            reason: "Network error".to_owned(),
+        };
+        assert!(!UpdateState::Idle.allows(&downloading(0)));
+        assert!(!UpdateState::Checking.allows(&UpdateState::ReadyToReboot));
+        assert!(!downloading(100).allows(&UpdateState::ReadyToReboot));
+        assert!(!UpdateState::ReadyToReboot.allows(&UpdateState::Idle));
+        assert!(UpdateState::ReadyToReboot.allows(&failed));
+        assert!(failed.allows(&UpdateState::Checking));
+    }
+
+    #[test]
+    fn stale_manifest_has_its_own_state() {
+        let stale = UpdateState::failed(&StaleError::ClockNotSet.into());
+        assert!(matches!(stale, UpdateState::Stale { .. }));
+        assert!(stale.allows(&UpdateState::Checking));
+
+        let failed = UpdateState::failed(&OtaError::HttpStatus(404));
+        assert!(matches!(failed, UpdateState::Failed { .. }));
+    }
+
+    #[test]
+    fn subscribers_receive_states_entered() {
+        let state = StateHandle::new();
+        let receiver = state.subscribe();
+        state.set(UpdateState::Checking);
+        state.set(UpdateState::Idle);
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            [UpdateState::Checking, UpdateState::Idle]
+        );
+    }
+
+    #[test]
+    fn stalled_subscriber_misses_states() {
+        let state = StateHandle::new();
+        let stalled = state.subscribe();
+        for bytes in 0..100 {
+            state.set(downloading(bytes));
+        }
+        let received: Vec<_> = stalled.try_iter().collect();
+        assert_eq!(received.len(), SUBSCRIBER_QUEUE_LEN);
+        assert_eq!(received[0], downloading(0));
+
+        // Still subscribed once it reads again.
+        state.set(UpdateState::Verifying);
+        assert_eq!(stalled.try_recv(), Ok(UpdateState::Verifying));
+        assert_eq!(state.get(), UpdateState::Verifying);
+    }
+
+    #[test]
+    fn dropped_subscriber_is_forgotten() {
+        let state = StateHandle::new();
+        drop(state.subscribe());
+        state.set(UpdateState::Checking);
+        assert!(state.0.lock().unwrap().subscribers.is_empty());
+    }
+}
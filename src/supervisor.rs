@@ -0,0 +1,163 @@
+use anyhow::Result;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_ota_template::app::{Application, Context};
+use log::{error, info, warn};
+use std::{
+    any::Any,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    health::{self, NAMESPACE, REASON_MAX_LEN},
+    CONFIG,
+};
+
+const CRASH_REASON: &str = "crash_why";
+const CRASH_REBOOTS: &str = "crash_reboots";
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Runs the application on its own thread and keeps it running.
+///
+/// When it fails or panics it is restarted with a doubling delay, up to
+/// `app_max_restarts` times. Past that the chip reboots, up to
+/// `app_max_crash_reboots` times, and then the image is rolled back. Running
+/// for `app_stable_secs` resets both counts. The last crash reason is kept in
+/// NVS and handed to `Application::on_crash` on the next boot.
+///
+/// An application whose `init` failed is not run. The updater gets
+/// `app_init_grace_secs` to install a fix before the chip reboots or the
+/// image is rolled back, as for an application that keeps failing.
+pub fn spawn(
+    app: Arc<dyn Application>,
+    ctx: Context,
+    nvs: EspDefaultNvsPartition,
+    init: Result<()>,
+) -> Result<JoinHandle<()>> {
+    if let Some(reason) = take_crash_reason(&nvs)? {
+        warn!("Last application crash: {}", reason);
+        app.on_crash(&reason);
+    }
+    Ok(thread::spawn(move || match init {
+        Ok(()) => supervise(app, ctx, nvs),
+        Err(e) => init_failed(&nvs, &format!("Application init failed: {}", e)),
+    }))
+}
+
+fn init_failed(nvs: &EspDefaultNvsPartition, reason: &str) -> ! {
+    error!("{}", reason);
+    if let Err(e) = record_crash(nvs, reason) {
+        error!("Could not record crash reason: {}", e);
+    }
+    let grace = Duration::from_secs(CONFIG.app_init_grace_secs);
+    warn!("Waiting {:?} for an update before giving up", grace);
+    thread::sleep(grace);
+    give_up(nvs, reason)
+}
+
+fn supervise(app: Arc<dyn Application>, ctx: Context, nvs: EspDefaultNvsPartition) {
+    let stable = Duration::from_secs(CONFIG.app_stable_secs);
+    let mut restarts = 0;
+
+    loop {
+        let started = Instant::now();
+        let worker = {
+            let app = app.clone();
+            let ctx = ctx.clone();
+            thread::spawn(move || app.run(ctx))
+        };
+
+        let mut is_stable = false;
+        while !worker.is_finished() {
+            if !is_stable && started.elapsed() >= stable {
+                is_stable = true;
+                restarts = 0;
+                if let Err(e) = set_crash_reboots(&nvs, 0) {
+                    warn!("Could not reset crash reboot count: {}", e);
+                }
+            }
+            thread::sleep(WATCH_INTERVAL);
+        }
+
+        let reason = match worker.join() {
+            Ok(Ok(())) => {
+                info!("Application stopped");
+                return;
+            }
+            Ok(Err(e)) => format!("Application failed: {}", e),
+            Err(panic) => format!("Application panicked: {}", panic_message(&*panic)),
+        };
+        error!("{}", reason);
+        if let Err(e) = record_crash(&nvs, &reason) {
+            error!("Could not record crash reason: {}", e);
+        }
+
+        restarts += 1;
+        if restarts <= CONFIG.app_max_restarts {
+            let delay = Duration::from_secs(CONFIG.app_restart_backoff_secs)
+                .saturating_mul(1 << (restarts - 1).min(16));
+            warn!(
+                "Restarting application in {:?} ({} of {})",
+                delay, restarts, CONFIG.app_max_restarts
+            );
+            thread::sleep(delay);
+            continue;
+        }
+
+        give_up(&nvs, &reason);
+    }
+}
+
+/// Reboots, up to `app_max_crash_reboots` times in a row, then rolls back.
+fn give_up(nvs: &EspDefaultNvsPartition, reason: &str) -> ! {
+    let reboots = crash_reboots(nvs).unwrap_or(0);
+    if reboots < CONFIG.app_max_crash_reboots {
+        if let Err(e) = set_crash_reboots(nvs, reboots + 1) {
+            error!("Could not record crash reboot: {}", e);
+        }
+        error!("Application keeps failing, rebooting");
+        unsafe { esp_idf_sys::esp_restart() }
+    }
+
+    // Start from scratch on the image we go back to.
+    let _ = set_crash_reboots(nvs, 0);
+    health::rollback(nvs, reason)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown payload"
+    }
+}
+
+fn record_crash(nvs: &EspDefaultNvsPartition, reason: &str) -> Result<()> {
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    nvs.set_str(CRASH_REASON, health::truncate(reason))?;
+    Ok(())
+}
+
+fn take_crash_reason(nvs: &EspDefaultNvsPartition) -> Result<Option<String>> {
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    let mut buf = [0_u8; REASON_MAX_LEN + 1];
+    let reason = nvs.get_str(CRASH_REASON, &mut buf)?.map(str::to_owned);
+    if reason.is_some() {
+        nvs.remove(CRASH_REASON)?;
+    }
+    Ok(reason)
+}
+
+fn crash_reboots(nvs: &EspDefaultNvsPartition) -> Result<u8> {
+    let nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    Ok(nvs.get_u8(CRASH_REBOOTS)?.unwrap_or(0))
+}
+
+fn set_crash_reboots(nvs: &EspDefaultNvsPartition, reboots: u8) -> Result<()> {
+    let mut nvs = EspNvs::new(nvs.clone(), NAMESPACE, true)?;
+    nvs.set_u8(CRASH_REBOOTS, reboots)?;
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Body of a response, read until it returns 0.
+pub trait Body {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+}
+
+/// The parts of an HTTP response the updater looks at.
+pub struct Response<B> {
+    pub status: u16,
+    pub content_length: Option<u64>,
+    pub content_range: Option<String>,
+    pub body: B,
+}
+
+/// Fetches manifests and images.
+pub trait Transport {
+    type Body: Body;
+
+    /// GETs `url`, asking for the bytes from `range_start` on when it is not 0.
+    fn get(&mut self, url: &str, range_start: u64) -> Result<Response<Self::Body>>;
+}
+
+/// Serves resources from memory, and can be told to misbehave the way real
+/// servers and connections do.
+#[derive(Default)]
+pub struct MemTransport {
+    resources: HashMap<String, Vec<u8>>,
+    ignore_ranges: bool,
+    cut_after: Option<usize>,
+    content_length: Option<Option<u64>>,
+}
+
+impl MemTransport {
+    pub fn new() -> MemTransport {
+        MemTransport::default()
+    }
+
+    pub fn insert(&mut self, url: &str, data: impl Into<Vec<u8>>) {
+        self.resources.insert(url.to_owned(), data.into());
+    }
+
+    /// Answers range requests with the whole resource, as some servers do.
+    pub fn ignore_ranges(&mut self, ignore: bool) {
+        self.ignore_ranges = ignore;
+    }
+
+    /// Ends every response body after `bytes`, as a dropped connection would.
+    pub fn cut_after(&mut self, bytes: Option<usize>) {
+        self.cut_after = bytes;
+    }
+
+    /// Announces `length` as Content-Length, or none, whatever the body is.
+    pub fn content_length(&mut self, length: Option<u64>) {
+        self.content_length = Some(length);
+    }
+}
+
+impl Transport for MemTransport {
+    type Body = MemBody;
+
+    fn get(&mut self, url: &str, range_start: u64) -> Result<Response<MemBody>> {
+        let Some(data) = self.resources.get(url) else {
+            return Ok(Response {
+                status: 404,
+                content_length: Some(0),
+                content_range: None,
+                body: MemBody::default(),
+            });
+        };
+
+        let start = if self.ignore_ranges {
+            0
+        } else {
+            (range_start as usize).min(data.len())
+        };
+        let (status, content_range) = if start > 0 {
+            let range = format!("bytes {}-{}/{}", start, data.len() - 1, data.len());
+            (206, Some(range))
+        } else {
+            (200, None)
+        };
+        let body = &data[start..];
+        let sent = self.cut_after.map_or(body.len(), |n| n.min(body.len()));
+
+        Ok(Response {
+            status,
+            content_length: self.content_length.unwrap_or(Some(body.len() as u64)),
+            content_range,
+            body: MemBody {
+                data: body[..sent].to_vec(),
+                pos: 0,
+            },
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct MemBody {
+    data: Vec<u8>,
+    pos: usize,
+}
+
+impl Body for MemBody {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let size = buf.len().min(self.data.len() - self.pos);
+        buf[..size].copy_from_slice(&self.data[self.pos..self.pos + size]);
+        self.pos += size;
+        Ok(size)
+    }
+}
@@ -0,0 +1,1289 @@
+use anyhow::anyhow;
+use core::fmt;
+use log::{info, warn};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{
+    blocklist::Blocklist,
+    channel::Channel,
+    error::{ManifestError, OtaError, PolicyError, StaleError},
+    image::{self, ImageError},
+    integrity::{ImageVerifier, IntegrityError},
+    progress::ProgressStore,
+    rollout,
+    sequence::SequenceStore,
+    signature,
+    sink::OtaSink,
+    state::{StateHandle, UpdateState},
+    transport::{Body, Transport},
+};
+
+const PROGRESS_SAVE_INTERVAL: u64 = 64 * 1024;
+/// How far ahead of our clock a manifest's `issued_at` may be.
+const MAX_CLOCK_SKEW: u64 = 5 * 60;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct UpdateJson {
+    pub version: String,
+    /// The image of this release built for each chip and board.
+    pub artifacts: Vec<ArtifactJson>,
+    /// Percentage of devices the release is offered to, all when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollout: Option<u8>,
+    /// Picks which devices are in the rollout, the version when missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rollout_seed: Option<String>,
+    /// Oldest version the release may be installed over.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_from_version: Option<String>,
+    /// Devices older than a mandatory release have to install it before any
+    /// newer one, e.g. because it migrates data the newer ones expect.
+    #[serde(default)]
+    pub mandatory: bool,
+}
+
+impl UpdateJson {
+    /// The artifact built for `target`, preferring one listing its board over
+    /// one for any board of the chip.
+    pub fn artifact(&self, target: &Target) -> Option<&ArtifactJson> {
+        let for_chip = || self.artifacts.iter().filter(|a| a.chip == target.chip);
+        for_chip()
+            .find(|a| a.boards.contains(&target.board))
+            .or_else(|| for_chip().find(|a| a.boards.is_empty()))
+    }
+
+    pub fn rollout(&self) -> u8 {
+        self.rollout.unwrap_or(100).min(100)
+    }
+
+    /// Whether the rollout has reached `device_id` yet.
+    pub fn is_rolled_out_to(&self, device_id: &str) -> bool {
+        let seed = self.rollout_seed.as_deref().unwrap_or(&self.version);
+        rollout::bucket(seed, device_id) < self.rollout()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct ArtifactJson {
+    /// ESP-IDF target name, e.g. `esp32` or `esp32c3`.
+    pub chip: String,
+    /// Board IDs, including the hardware revision, the image is for. Any
+    /// board of the chip when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub boards: Vec<String>,
+    pub link: String,
+    /// Other places serving the same image, tried when `link` fails.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<String>,
+    pub sha256: String,
+    pub size: u64,
+}
+
+/// What a device runs on, to pick the artifact built for it.
+#[derive(Clone, Debug)]
+pub struct Target {
+    pub chip: String,
+    pub board: String,
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} board {:?}", self.chip, self.board)
+    }
+}
+
+/// An update described by a manifest whose signature has been verified, so
+/// `sha256` pins the image that may be flashed.
+#[derive(Debug)]
+pub struct Update {
+    pub version: Version,
+    /// The artifact's link followed by its mirrors.
+    pub links: Vec<String>,
+    pub sha256: String,
+    pub size: u64,
+}
+
+impl Update {
+    pub fn new(json: UpdateJson, artifact: ArtifactJson) -> Result<Update, OtaError> {
+        let version = Version::parse(&json.version)?;
+        let mut links = vec![artifact.link];
+        links.extend(artifact.mirrors);
+        let sha256 = artifact.sha256;
+        let size = artifact.size;
+        Ok(Update {
+            version,
+            links,
+            sha256,
+            size,
+        })
+    }
+}
+
+/// What a device brings to picking its update from a manifest.
+pub struct DeviceInfo<'a> {
+    pub id: &'a str,
+    pub target: &'a Target,
+    /// The version running now.
+    pub current: &'a Version,
+    /// Newest version to update to, set on the device.
+    pub pin: Option<&'a Version>,
+    pub blocklist: &'a Blocklist,
+}
+
+/// A manifest whose signature has been verified, with the releases of each
+/// channel by channel name.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    /// Raised with every manifest published, never accepted going down.
+    pub sequence: u64,
+    /// Validity period, in seconds since the Unix epoch.
+    pub issued_at: u64,
+    pub expires_at: u64,
+    pub channels: BTreeMap<String, Vec<UpdateJson>>,
+    /// Devices moved to another channel, by device ID.
+    #[serde(default)]
+    pub assignments: BTreeMap<String, Channel>,
+    /// Exact version to run by channel name, the way to roll a channel back.
+    #[serde(default)]
+    pub targets: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// The release on `channel` for `device` to install, if any.
+    ///
+    /// When the manifest sets a target version for the channel, that exact
+    /// release, even if older than the current one. Otherwise the newest
+    /// release whose `min_from_version` allows the current one, not past the
+    /// first mandatory release newer than it. Either way only releases rolled
+    /// out to the device, not newer than its pin and whose image is not on its
+    /// blocklist.
+    pub fn update(
+        &self,
+        channel: Channel,
+        device: &DeviceInfo,
+    ) -> Result<Option<Update>, OtaError> {
+        let DeviceInfo {
+            id: device_id,
+            target,
+            current,
+            pin,
+            blocklist,
+        } = *device;
+        let Some(releases) = self.channels.get(channel.as_str()) else {
+            info!("No release on the {} channel", channel);
+            return Ok(None);
+        };
+        let mut versions = Vec::new();
+        for json in releases {
+            let version = Version::parse(&json.version)?;
+            if !version.pre.is_empty() && !channel.allows_prerelease() {
+                warn!(
+                    "Ignoring pre-release {} on the {} channel",
+                    version, channel
+                );
+                continue;
+            }
+            versions.push((version, json));
+        }
+        let pinned = |version: &Version| matches!(pin, Some(pin) if version > pin);
+
+        if let Some(wanted) = self.targets.get(channel.as_str()) {
+            let wanted = Version::parse(wanted)?;
+            if wanted == *current {
+                return Ok(None);
+            }
+            if pinned(&wanted) {
+                info!("Target {} is past the pinned {:?}", wanted, pin);
+                return Ok(None);
+            }
+            let Some((_, json)) = versions.iter().find(|(version, _)| *version == wanted) else {
+                warn!("Target {} is not published on {}", wanted, channel);
+                return Ok(None);
+            };
+            if !json.is_rolled_out_to(device_id) {
+                info!("Target {} is not rolled out to this device yet", wanted);
+                return Ok(None);
+            }
+            if matches!(json.artifact(target), Some(a) if blocklist.contains(&a.sha256)) {
+                warn!("Target {} was rolled back on this device before", wanted);
+                return Ok(None);
+            }
+            if wanted < *current {
+                info!("Manifest targets {}, going back from {}", wanted, current);
+            }
+            return release(json, target);
+        }
+
+        versions.retain(|(version, _)| version > current && !pinned(version));
+        versions.sort_by(|a, b| a.0.cmp(&b.0));
+        let latest = versions.last().map(|(version, _)| version.clone());
+
+        let mut best = None;
+        for (version, json) in versions {
+            let reachable = match &json.min_from_version {
+                Some(min) => Version::parse(min)? <= *current,
+                None => true,
+            };
+            match json.artifact(target) {
+                Some(_) if !reachable => {
+                    info!("{} needs at least {:?}", version, json.min_from_version)
+                }
+                Some(_) if !json.is_rolled_out_to(device_id) => info!(
+                    "{} is rolled out to {}% of devices, not this one yet",
+                    version,
+                    json.rollout()
+                ),
+                Some(artifact) if blocklist.contains(&artifact.sha256) => {
+                    warn!("{} was rolled back on this device before", version)
+                }
+                Some(_) => best = Some(json),
+                None => info!("{} has no artifact for {}", version, target),
+            }
+            if json.mandatory {
+                break;
+            }
+        }
+
+        let Some(json) = best else {
+            if let Some(latest) = latest {
+                warn!("No path from {} towards {} on {}", current, latest, channel);
+            }
+            return Ok(None);
+        };
+        let update = release(json, target)?;
+        if let Some((update, latest)) = update.as_ref().zip(latest) {
+            if latest != update.version {
+                info!(
+                    "Installing {} first, on the way to {}",
+                    update.version, latest
+                );
+            }
+        }
+        Ok(update)
+    }
+
+    /// The channel the manifest assigns to `device_id`, if any.
+    pub fn assignment(&self, device_id: &str) -> Option<Channel> {
+        self.assignments.get(device_id).copied()
+    }
+}
+
+/// `json` as an update for `target`, if it has an artifact for it.
+fn release(json: &UpdateJson, target: &Target) -> Result<Option<Update>, OtaError> {
+    let Some(artifact) = json.artifact(target) else {
+        info!("{} has no artifact for {}", json.version, target);
+        return Ok(None);
+    };
+    Ok(Some(Update::new(json.clone(), artifact.clone())?))
+}
+
+/// What the updater needs to know that does not come from the manifest.
+pub struct UpdaterConfig {
+    /// Hex encoded Ed25519 key manifests are signed with.
+    pub public_key: String,
+    pub manifest_max_size: u64,
+    /// Project name and chip id images have to be built for.
+    pub project_name: String,
+    pub chip_id: u16,
+    /// Lowest app `secure_version` the bootloader still boots, 0 without
+    /// anti-rollback.
+    pub min_secure_version: u32,
+}
+
+/// Checks for, downloads, verifies and commits updates, independent of how
+/// they are fetched and where they are written to.
+pub struct Updater<T> {
+    transport: T,
+    config: UpdaterConfig,
+    state: StateHandle,
+    /// Failed attempts by manifest or image URL since it last worked, less
+    /// one for every success of another URL tried along with it.
+    failures: BTreeMap<String, u32>,
+}
+
+impl<T: Transport> Updater<T> {
+    /// The updater moves `state` through checking, downloading, verifying and
+    /// ready to reboot, the caller handles the rest of the transitions.
+    pub fn new(transport: T, config: UpdaterConfig, state: StateHandle) -> Updater<T> {
+        Updater {
+            transport,
+            config,
+            state,
+            failures: BTreeMap::new(),
+        }
+    }
+
+    /// Fetches and verifies the manifest from the first of `urls` that serves
+    /// a valid one, trying the ones that failed before last. A manifest older
+    /// than the highest in `sequences` is refused, a newer one raises it, and
+    /// one not valid at `now`, Unix time if the clock is set, is stale.
+    pub fn fetch_manifest(
+        &mut self,
+        urls: &[String],
+        sequences: &mut impl SequenceStore,
+        now: Option<u64>,
+    ) -> Result<Manifest, OtaError> {
+        self.state.set_unless_rolled_back(UpdateState::Checking);
+        let mut last = None;
+        for url in self.ranked(urls) {
+            match self.fetch_manifest_from(&url, sequences, now) {
+                Ok(manifest) => {
+                    self.record(urls, &url, true);
+                    return Ok(manifest);
+                }
+                Err(e) => {
+                    warn!("Manifest from {} failed: {}", url, e);
+                    self.record(urls, &url, false);
+                    last = Some(e);
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| OtaError::Network(anyhow!("No manifest URL configured"))))
+    }
+
+    fn fetch_manifest_from(
+        &mut self,
+        url: &str,
+        sequences: &mut impl SequenceStore,
+        now: Option<u64>,
+    ) -> Result<Manifest, OtaError> {
+        let response = self.transport.get(url, 0).map_err(OtaError::Network)?;
+        let status = response.status;
+        if !(200..=299).contains(&status) {
+            return Err(OtaError::HttpStatus(status));
+        }
+
+        let body = self.read_manifest(response.content_length, response.body)?;
+        let manifest = signature::verify_manifest(&body, &self.config.public_key)?;
+        let manifest: Manifest = serde_json::from_value(manifest).map_err(ManifestError::Json)?;
+
+        let now = now.ok_or(StaleError::ClockNotSet)?;
+        if now >= manifest.expires_at {
+            return Err(StaleError::Expired {
+                expires_at: manifest.expires_at,
+                now,
+            }
+            .into());
+        }
+        if manifest.issued_at > now + MAX_CLOCK_SKEW {
+            return Err(StaleError::NotYetValid {
+                issued_at: manifest.issued_at,
+                now,
+            }
+            .into());
+        }
+
+        let highest = sequences.highest().map_err(OtaError::Flash)?;
+        if manifest.sequence < highest {
+            return Err(PolicyError::Replayed {
+                sequence: manifest.sequence,
+                highest,
+            }
+            .into());
+        }
+        if manifest.sequence > highest {
+            sequences
+                .accept(manifest.sequence)
+                .map_err(OtaError::Flash)?;
+        }
+        Ok(manifest)
+    }
+
+    /// Reads a manifest body to completion, bounded by `manifest_max_size`.
+    fn read_manifest(
+        &self,
+        content_len: Option<u64>,
+        mut reader: T::Body,
+    ) -> Result<Vec<u8>, OtaError> {
+        let limit = self.config.manifest_max_size;
+        if let Some(size) = content_len.filter(|size| *size > limit) {
+            return Err(ManifestError::TooLarge { limit, size }.into());
+        }
+
+        let mut body = Vec::new();
+        let mut buf = [0_u8; 256];
+        loop {
+            let size = reader.read(&mut buf).map_err(OtaError::Network)?;
+            if size == 0 {
+                break;
+            }
+            let received = (body.len() + size) as u64;
+            if let Some(expected) = content_len.filter(|expected| received > *expected) {
+                return Err(ManifestError::Overlong { expected }.into());
+            }
+            if received > limit {
+                return Err(ManifestError::TooLarge {
+                    limit,
+                    size: received,
+                }
+                .into());
+            }
+            body.extend_from_slice(&buf[..size]);
+        }
+
+        let received = body.len() as u64;
+        match content_len {
+            Some(expected) if received != expected => {
+                Err(ManifestError::Truncated { expected, received }.into())
+            }
+            _ if received == 0 => Err(ManifestError::Empty.into()),
+            _ => Ok(body),
+        }
+    }
+
+    /// Downloads `update` into `slot` and makes it the boot image, resuming from
+    /// `progress` when it belongs to the same image. The caller restarts the chip.
+    pub fn ota_update(
+        &mut self,
+        update: &Update,
+        slot: &mut impl OtaSink,
+        progress: &mut impl ProgressStore,
+    ) -> Result<(), OtaError> {
+        if update.size > slot.capacity() {
+            return Err(ImageError::TooLarge {
+                size: update.size,
+                partition: slot.label(),
+                capacity: slot.capacity(),
+            }
+            .into());
+        }
+        let mut last = None;
+        for link in self.ranked(&update.links) {
+            match self.download(update, &link, slot, progress) {
+                Ok(()) => {
+                    self.record(&update.links, &link, true);
+                    return Ok(());
+                }
+                // Not down to the source, another one will not do better.
+                Err(e @ OtaError::Flash(_)) => return Err(e),
+                Err(e) => {
+                    warn!("Download from {} failed: {}", link, e);
+                    self.record(&update.links, &link, false);
+                    last = Some(e);
+                }
+            }
+        }
+        Err(last.unwrap_or_else(|| OtaError::Network(anyhow!("No link to download from"))))
+    }
+
+    /// One attempt at `ota_update` from `link`.
+    fn download(
+        &mut self,
+        update: &Update,
+        link: &str,
+        slot: &mut impl OtaSink,
+        progress: &mut impl ProgressStore,
+    ) -> Result<(), OtaError> {
+        let mut verifier = ImageVerifier::new();
+
+        let mut offset = match progress.load().map_err(OtaError::Flash)? {
+            Some(p) if p.version == update.version.to_string() && p.sha256 == update.sha256 => {
+                p.written
+            }
+            _ => 0,
+        };
+        if offset > 0 {
+            info!("Rehashing {} bytes already in {}", offset, slot.label());
+            let mut buf = [0_u8; 4096];
+            let mut hashed = 0;
+            while hashed < offset {
+                let size = buf.len().min((offset - hashed) as usize);
+                slot.read(hashed, &mut buf[..size])
+                    .map_err(OtaError::Flash)?;
+                verifier.update(&buf[..size]);
+                hashed += size as u64;
+            }
+        }
+
+        let response = self
+            .transport
+            .get(link, offset)
+            .map_err(OtaError::Network)?;
+        let status = response.status;
+
+        match status {
+            206 if offset > 0 => {
+                let expected = format!("bytes {}-", offset);
+                let content_range = response.content_range.as_deref().unwrap_or_default();
+                if !content_range.starts_with(&expected) {
+                    progress.clear().map_err(OtaError::Flash)?;
+                    return Err(OtaError::Network(anyhow!(
+                        "Unexpected Content-Range: {}",
+                        content_range
+                    )));
+                }
+                info!("Resuming OTA at {} bytes", offset);
+            }
+            200..=299 => {
+                if offset > 0 {
+                    info!("Server ignored the range request, restarting download");
+                    offset = 0;
+                    verifier = ImageVerifier::new();
+                }
+                info!("Begin OTA");
+            }
+            _ => return Err(OtaError::HttpStatus(status)),
+        }
+
+        // What this response carries has to fit the slot and add up to the size
+        // pinned by the manifest.
+        if let Some(content_len) = response.content_length {
+            if offset + content_len > slot.capacity() {
+                return Err(ImageError::TooLarge {
+                    size: offset + content_len,
+                    partition: slot.label(),
+                    capacity: slot.capacity(),
+                }
+                .into());
+            }
+            if offset + content_len != update.size {
+                progress.clear().map_err(OtaError::Flash)?;
+                return Err(IntegrityError::ContentLengthMismatch {
+                    expected: update.size.saturating_sub(offset),
+                    content_length: content_len,
+                }
+                .into());
+            }
+        }
+
+        let mut reader = response.body;
+
+        // Nothing is written, and so nothing erased, until the stream is known to
+        // start with an app image.
+        let mut head = Vec::new();
+        if offset == 0 {
+            head = read_head(&mut reader, update.size)?;
+            let info = image::parse_header(&head)?;
+            info!(
+                "Image {} {} built with IDF {}",
+                info.project_name, info.version, info.idf_version
+            );
+            image::check_app(
+                &info,
+                &self.config.project_name,
+                self.config.chip_id,
+                &update.version,
+                self.config.min_secure_version,
+            )?;
+        }
+
+        self.state.set(UpdateState::Downloading {
+            bytes: offset,
+            total: update.size,
+        });
+        slot.seek(offset);
+        progress
+            .start(&update.version.to_string(), &update.sha256, offset)
+            .map_err(OtaError::Flash)?;
+
+        if !head.is_empty() {
+            verifier.update(&head);
+            slot.write(0, &head).map_err(OtaError::Flash)?;
+            offset = head.len() as u64;
+        }
+
+        let mut buf = [0_u8; 256];
+        let mut saved = offset;
+        loop {
+            let size = reader.read(&mut buf).map_err(OtaError::Network)?;
+            info!("Read {} bytes", size);
+            if size == 0 {
+                break;
+            }
+            if offset + size as u64 > update.size {
+                progress.clear().map_err(OtaError::Flash)?;
+                return Err(IntegrityError::SizeMismatch {
+                    expected: update.size,
+                    actual: offset + size as u64,
+                }
+                .into());
+            }
+            verifier.update(&buf[..size]);
+            slot.write(offset, &buf[..size]).map_err(OtaError::Flash)?;
+            offset += size as u64;
+            info!("Wrote {} bytes", size);
+            if offset - saved >= PROGRESS_SAVE_INTERVAL {
+                progress.set_written(offset).map_err(OtaError::Flash)?;
+                saved = offset;
+                self.state.set(UpdateState::Downloading {
+                    bytes: offset,
+                    total: update.size,
+                });
+            }
+        }
+        progress.set_written(offset).map_err(OtaError::Flash)?;
+
+        // The connection ended early, keep what we have so the next attempt resumes.
+        if offset < update.size {
+            return Err(OtaError::Incomplete {
+                expected: update.size,
+                received: offset,
+            });
+        }
+
+        self.state.set(UpdateState::Verifying);
+        info!("Verifying {} bytes", verifier.written());
+        if let Err(e) = verifier.verify(update.size, &update.sha256) {
+            progress.clear().map_err(OtaError::Flash)?;
+            return Err(e.into());
+        }
+
+        slot.commit().map_err(OtaError::Flash)?;
+        progress.clear().map_err(OtaError::Flash)?;
+        self.state.set(UpdateState::ReadyToReboot);
+        info!("OTA Complete");
+        Ok(())
+    }
+
+    /// `urls` with the ones that failed least first, in their given order
+    /// otherwise.
+    fn ranked(&self, urls: &[String]) -> Vec<String> {
+        let mut urls = urls.to_vec();
+        urls.sort_by_key(|url| self.failures.get(url).copied().unwrap_or(0));
+        urls
+    }
+
+    /// Counts a failure of `url`, or forgets its failures on success. The
+    /// others in `urls` then decay, so the first URL is back in front once it
+    /// works again rather than staying behind the one that stood in for it.
+    fn record(&mut self, urls: &[String], url: &str, ok: bool) {
+        if ok {
+            self.failures.remove(url);
+            for other in urls {
+                if let Some(failures) = self.failures.get_mut(other) {
+                    *failures -= 1;
+                }
+            }
+            self.failures.retain(|_, failures| *failures > 0);
+        } else {
+            *self.failures.entry(url.to_owned()).or_default() += 1;
+        }
+    }
+}
+
+/// Reads the bytes needed to validate an image before anything is flashed.
+fn read_head(reader: &mut impl Body, image_size: u64) -> Result<Vec<u8>, OtaError> {
+    let len = (image::HEADER_LEN as u64).min(image_size) as usize;
+    let mut head = vec![0_u8; len];
+    let mut filled = 0;
+    while filled < len {
+        let size = reader
+            .read(&mut head[filled..])
+            .map_err(OtaError::Network)?;
+        if size == 0 {
+            return Err(OtaError::Incomplete {
+                expected: len as u64,
+                received: filled as u64,
+            });
+        }
+        filled += size;
+    }
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{blocklist::BlockedImage, signature::SignatureError};
+    use crate::{
+        progress::MemProgressStore,
+        sequence::{MemSequenceStore, SequenceStore},
+        sink::MemSink,
+        transport::MemTransport,
+    };
+    use sha2::{Digest, Sha256};
+
+    const LINK: &str = "http://ota.test/image.bin";
+    const PROJECT: &str = "esp-ota-template";
+    const IMAGE_LEN: usize = 200_000;
+    const DEVICE: &str = "240ac4000001";
+    const MANIFEST_URL: &str = "http://ota.test/manifest.json";
+    const SECRET: &str = "0707070707070707070707070707070707070707070707070707070707070707";
+    /// When the manifests from `signed` are valid.
+    const NOW: u64 = 1_800_000_000;
+
+    /// An app image for `PROJECT` on chip 0, filled up to `IMAGE_LEN`.
+    fn image(version: &str) -> Vec<u8> {
+        let mut data: Vec<u8> = (0..IMAGE_LEN).map(|i| (i % 251) as u8).collect();
+        data[0] = 0xE9;
+        data[12..14].copy_from_slice(&0_u16.to_le_bytes());
+        let desc = &mut data[32..288];
+        desc.fill(0);
+        desc[..4].copy_from_slice(&0xABCD_5432_u32.to_le_bytes());
+        desc[16..16 + version.len()].copy_from_slice(version.as_bytes());
+        desc[48..48 + PROJECT.len()].copy_from_slice(PROJECT.as_bytes());
+        data
+    }
+
+    fn update_for(data: &[u8]) -> Update {
+        Update {
+            version: Version::new(0, 2, 0),
+            links: vec![LINK.to_owned()],
+            sha256: hex::encode(Sha256::digest(data)),
+            size: data.len() as u64,
+        }
+    }
+
+    fn updater(transport: MemTransport) -> Updater<MemTransport> {
+        let mut secret = [0_u8; 32];
+        hex::decode_to_slice(SECRET, &mut secret).unwrap();
+        let public_key = ed25519_dalek::SigningKey::from_bytes(&secret).verifying_key();
+        let config = UpdaterConfig {
+            public_key: hex::encode(public_key.to_bytes()),
+            manifest_max_size: 4096,
+            project_name: PROJECT.to_owned(),
+            chip_id: 0,
+            min_secure_version: 0,
+        };
+        Updater::new(transport, config, StateHandle::new())
+    }
+
+    fn serving(data: &[u8]) -> MemTransport {
+        let mut transport = MemTransport::new();
+        transport.insert(LINK, data);
+        transport
+    }
+
+    /// `manifest` signed for `updater`, valid at `NOW`.
+    fn signed(mut manifest: serde_json::Value) -> Vec<u8> {
+        manifest["issued_at"] = (NOW - 60).into();
+        manifest["expires_at"] = (NOW + 3600).into();
+        let body = manifest.to_string();
+        manifest["signature"] = signature::sign_manifest(body.as_bytes(), SECRET)
+            .unwrap()
+            .into();
+        manifest.to_string().into_bytes()
+    }
+
+    fn target() -> Target {
+        Target {
+            chip: "esp32".to_owned(),
+            board: String::new(),
+        }
+    }
+
+    /// A manifest with `releases` on the stable channel, each with an esp32 image.
+    fn manifest(mut releases: serde_json::Value) -> Manifest {
+        for release in releases.as_array_mut().unwrap() {
+            let version = release["version"].as_str().unwrap().to_owned();
+            release["artifacts"] = serde_json::json!([{
+                "chip": "esp32",
+                "link": format!("http://ota.test/{}.bin", version),
+                "sha256": hex::encode(Sha256::digest(version.as_bytes())),
+                "size": IMAGE_LEN,
+            }]);
+        }
+        serde_json::from_value(serde_json::json!({
+            "sequence": 1,
+            "issued_at": 0,
+            "expires_at": 1,
+            "channels": { "stable": releases },
+        }))
+        .unwrap()
+    }
+
+    /// The version `manifest` offers on the stable channel to a device running `current`.
+    fn offered(manifest: &Manifest, current: &str, blocklist: &Blocklist) -> Option<Version> {
+        let current = Version::parse(current).unwrap();
+        let device = DeviceInfo {
+            id: DEVICE,
+            target: &target(),
+            current: &current,
+            pin: None,
+            blocklist,
+        };
+        let update = manifest.update(Channel::Stable, &device).unwrap();
+        update.map(|update| update.version)
+    }
+
+    #[test]
+    fn stable_skips_prereleases() {
+        let manifest = manifest(serde_json::json!([
+            { "version": "0.2.0" },
+            { "version": "0.3.0-rc.1" },
+        ]));
+        assert_eq!(
+            offered(&manifest, "0.1.0", &Blocklist::default()),
+            Some(Version::new(0, 2, 0))
+        );
+    }
+
+    #[test]
+    fn release_not_rolled_out_is_passed_over() {
+        let manifest = manifest(serde_json::json!([
+            { "version": "0.0.2" },
+            { "version": "0.0.3", "rollout": 0 },
+        ]));
+
+        assert_eq!(
+            offered(&manifest, "0.0.1", &Blocklist::default()),
+            Some(Version::new(0, 0, 2))
+        );
+    }
+
+    #[test]
+    fn mandatory_release_is_a_stepping_stone() {
+        let manifest = manifest(serde_json::json!([
+            { "version": "0.0.2", "mandatory": true },
+            { "version": "0.0.3" },
+        ]));
+        let none = Blocklist::default();
+
+        assert_eq!(
+            offered(&manifest, "0.0.1", &none),
+            Some(Version::new(0, 0, 2))
+        );
+        assert_eq!(
+            offered(&manifest, "0.0.2", &none),
+            Some(Version::new(0, 0, 3))
+        );
+        assert_eq!(offered(&manifest, "0.0.3", &none), None);
+    }
+
+    #[test]
+    fn release_out_of_reach_is_passed_over() {
+        let manifest = manifest(serde_json::json!([
+            { "version": "0.0.2" },
+            { "version": "0.0.3", "min_from_version": "0.0.2" },
+        ]));
+        let none = Blocklist::default();
+
+        assert_eq!(
+            offered(&manifest, "0.0.1", &none),
+            Some(Version::new(0, 0, 2))
+        );
+        assert_eq!(
+            offered(&manifest, "0.0.2", &none),
+            Some(Version::new(0, 0, 3))
+        );
+    }
+
+    #[test]
+    fn no_path_to_latest_offers_nothing() {
+        let unreachable = manifest(serde_json::json!([
+            { "version": "0.0.3", "min_from_version": "0.0.2" },
+        ]));
+        assert_eq!(offered(&unreachable, "0.0.1", &Blocklist::default()), None);
+
+        // Nothing past a mandatory release the device cannot reach either.
+        let blocked_by_mandatory = manifest(serde_json::json!([
+            { "version": "0.0.3", "min_from_version": "0.0.2", "mandatory": true },
+            { "version": "0.0.4" },
+        ]));
+        assert_eq!(
+            offered(&blocked_by_mandatory, "0.0.1", &Blocklist::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn rolled_back_image_is_passed_over() {
+        let mut manifest = manifest(serde_json::json!([
+            { "version": "0.0.2" },
+            { "version": "0.0.3" },
+        ]));
+        let mut blocklist = Blocklist::default();
+        blocklist.block(BlockedImage {
+            version: "0.0.3".to_owned(),
+            sha256: hex::encode(Sha256::digest(b"0.0.3")).to_uppercase(),
+        });
+        // What is written to and read back from NVS.
+        let blocklist = Blocklist::parse(&blocklist.to_string());
+
+        assert_eq!(
+            offered(&manifest, "0.0.1", &blocklist),
+            Some(Version::new(0, 0, 2))
+        );
+        assert_eq!(offered(&manifest, "0.0.2", &blocklist), None);
+
+        // Rebuilt and published again, the release is installed.
+        manifest.channels.get_mut("stable").unwrap()[1].artifacts[0].sha256 = "ab".repeat(32);
+        assert_eq!(
+            offered(&manifest, "0.0.2", &blocklist),
+            Some(Version::new(0, 0, 3))
+        );
+    }
+
+    #[test]
+    fn fetch_manifest_verifies_and_parses() {
+        let mut transport = MemTransport::new();
+        transport.insert(
+            MANIFEST_URL,
+            signed(serde_json::json!({
+                "sequence": 1,
+                "channels": { "stable": [] },
+                "assignments": { DEVICE: "beta" },
+            })),
+        );
+        let mut updater = updater(transport);
+
+        let urls = [MANIFEST_URL.to_owned()];
+        let manifest = updater
+            .fetch_manifest(&urls, &mut MemSequenceStore::new(), Some(NOW))
+            .unwrap();
+        assert_eq!(manifest.sequence, 1);
+        assert_eq!(manifest.assignment(DEVICE), Some(Channel::Beta));
+        assert_eq!(updater.state.get(), UpdateState::Checking);
+    }
+
+    /// Serves a manifest with `sequence` and nothing to install.
+    fn serving_sequence(sequence: u64) -> MemTransport {
+        let mut transport = MemTransport::new();
+        transport.insert(
+            MANIFEST_URL,
+            signed(serde_json::json!({
+                "sequence": sequence,
+                "channels": { "stable": [] },
+            })),
+        );
+        transport
+    }
+
+    #[test]
+    fn replayed_manifest_is_refused() {
+        let mut sequences = MemSequenceStore::new();
+        sequences.accept(5).unwrap();
+        let mut updater = updater(serving_sequence(4));
+
+        let urls = [MANIFEST_URL.to_owned()];
+        let e = updater
+            .fetch_manifest(&urls, &mut sequences, Some(NOW))
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            OtaError::Policy(PolicyError::Replayed {
+                sequence: 4,
+                highest: 5
+            })
+        ));
+        assert!(!e.is_transient());
+        assert_eq!(sequences.highest().unwrap(), 5);
+    }
+
+    #[test]
+    fn newer_sequence_is_stored() {
+        let mut sequences = MemSequenceStore::new();
+        sequences.accept(5).unwrap();
+        let urls = [MANIFEST_URL.to_owned()];
+
+        // The same manifest again is fine, as every poll fetches it.
+        updater(serving_sequence(5))
+            .fetch_manifest(&urls, &mut sequences, Some(NOW))
+            .unwrap();
+        assert_eq!(sequences.highest().unwrap(), 5);
+
+        updater(serving_sequence(6))
+            .fetch_manifest(&urls, &mut sequences, Some(NOW))
+            .unwrap();
+        assert_eq!(sequences.highest().unwrap(), 6);
+    }
+
+    /// What fetching a manifest valid from `NOW - 60` to `NOW + 3600` at `now` fails with.
+    fn stale_error(now: Option<u64>) -> OtaError {
+        let urls = [MANIFEST_URL.to_owned()];
+        updater(serving_sequence(1))
+            .fetch_manifest(&urls, &mut MemSequenceStore::new(), now)
+            .unwrap_err()
+    }
+
+    #[test]
+    fn expired_manifest_is_refused() {
+        let e = stale_error(Some(NOW + 3600));
+        assert!(matches!(
+            e,
+            OtaError::Stale(StaleError::Expired { expires_at, now })
+                if expires_at == NOW + 3600 && now == NOW + 3600
+        ));
+        assert!(e.is_transient());
+    }
+
+    #[test]
+    fn manifest_from_the_future_is_refused() {
+        // Clock skew is allowed for.
+        let urls = [MANIFEST_URL.to_owned()];
+        updater(serving_sequence(1))
+            .fetch_manifest(
+                &urls,
+                &mut MemSequenceStore::new(),
+                Some(NOW - 60 - MAX_CLOCK_SKEW),
+            )
+            .unwrap();
+
+        let e = stale_error(Some(NOW - 61 - MAX_CLOCK_SKEW));
+        assert!(matches!(
+            e,
+            OtaError::Stale(StaleError::NotYetValid { issued_at, .. }) if issued_at == NOW - 60
+        ));
+    }
+
+    #[test]
+    fn manifest_is_refused_until_clock_is_set() {
+        let e = stale_error(None);
+        assert!(matches!(e, OtaError::Stale(StaleError::ClockNotSet)));
+        assert!(e.is_transient());
+    }
+
+    #[test]
+    fn unsigned_manifest_is_refused() {
+        let mut transport = MemTransport::new();
+        transport.insert(MANIFEST_URL, r#"{"sequence": 1, "channels": {}}"#);
+        let mut updater = updater(transport);
+
+        let urls = [MANIFEST_URL.to_owned()];
+        let e = updater
+            .fetch_manifest(&urls, &mut MemSequenceStore::new(), Some(NOW))
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            OtaError::Manifest(ManifestError::Signature(SignatureError::Missing))
+        ));
+        assert!(!e.is_transient());
+    }
+
+    /// What fetching the manifest `transport` serves at `MANIFEST_URL` fails with.
+    fn fetch_error(transport: MemTransport) -> OtaError {
+        let urls = [MANIFEST_URL.to_owned()];
+        updater(transport)
+            .fetch_manifest(&urls, &mut MemSequenceStore::new(), Some(NOW))
+            .unwrap_err()
+    }
+
+    #[test]
+    fn oversized_manifest_is_refused() {
+        let mut transport = MemTransport::new();
+        transport.insert(MANIFEST_URL, vec![b' '; 5000]);
+        let e = fetch_error(transport);
+        assert!(matches!(
+            e,
+            OtaError::Manifest(ManifestError::TooLarge {
+                limit: 4096,
+                size: 5000
+            })
+        ));
+
+        // Without a Content-Length it is found out while reading.
+        let mut transport = MemTransport::new();
+        transport.insert(MANIFEST_URL, vec![b' '; 5000]);
+        transport.content_length(None);
+        let e = fetch_error(transport);
+        assert!(matches!(
+            e,
+            OtaError::Manifest(ManifestError::TooLarge { limit: 4096, .. })
+        ));
+        assert!(!e.is_transient());
+    }
+
+    #[test]
+    fn manifest_shorter_or_longer_than_announced_is_refused() {
+        let mut transport = MemTransport::new();
+        transport.insert(MANIFEST_URL, vec![b' '; 1000]);
+        transport.cut_after(Some(600));
+        let e = fetch_error(transport);
+        assert!(matches!(
+            e,
+            OtaError::Manifest(ManifestError::Truncated {
+                expected: 1000,
+                received: 600
+            })
+        ));
+        assert!(e.is_transient());
+
+        let mut transport = MemTransport::new();
+        transport.insert(MANIFEST_URL, vec![b' '; 1000]);
+        transport.content_length(Some(600));
+        let e = fetch_error(transport);
+        assert!(matches!(
+            e,
+            OtaError::Manifest(ManifestError::Overlong { expected: 600 })
+        ));
+    }
+
+    #[test]
+    fn empty_manifest_is_refused() {
+        let mut transport = MemTransport::new();
+        transport.insert(MANIFEST_URL, "");
+        let e = fetch_error(transport);
+        assert!(matches!(e, OtaError::Manifest(ManifestError::Empty)));
+        assert!(e.is_transient());
+    }
+
+    #[test]
+    fn rollback_stays_visible_through_checks() {
+        let mut updater = updater(MemTransport::new());
+        let rolled_back = UpdateState::RolledBack {
+            reason: "Health check failed".to_owned(),
+        };
+        updater.state.set(rolled_back.clone());
+
+        let urls = ["http://ota.test/missing.json".to_owned()];
+        let e = updater
+            .fetch_manifest(&urls, &mut MemSequenceStore::new(), Some(0))
+            .unwrap_err();
+        assert!(matches!(e, OtaError::HttpStatus(404)));
+        assert_eq!(updater.state.get(), rolled_back);
+    }
+
+    #[test]
+    fn failed_link_goes_back_in_front() {
+        let data = image("0.2.0");
+        let mirror = "http://mirror.test/image.bin";
+        let mut update = update_for(&data);
+        update.links = vec![LINK.to_owned(), mirror.to_owned()];
+        let mut transport = MemTransport::new();
+        transport.insert(mirror, data.clone());
+        let mut updater = updater(transport);
+
+        updater
+            .ota_update(
+                &update,
+                &mut MemSink::new(1 << 20),
+                &mut MemProgressStore::new(),
+            )
+            .unwrap();
+        assert_eq!(updater.ranked(&update.links), update.links);
+    }
+
+    #[test]
+    fn full_download_commits() {
+        let data = image("0.2.0");
+        let mut updater = updater(serving(&data));
+        let mut slot = MemSink::new(1 << 20);
+        let mut progress = MemProgressStore::new();
+
+        updater
+            .ota_update(&update_for(&data), &mut slot, &mut progress)
+            .unwrap();
+
+        assert!(slot.is_committed());
+        assert_eq!(slot.data(), &data[..]);
+        assert!(progress.load().unwrap().is_none());
+        assert_eq!(updater.state.get(), UpdateState::ReadyToReboot);
+    }
+
+    #[test]
+    fn cut_download_resumes_from_progress() {
+        let data = image("0.2.0");
+        let update = update_for(&data);
+        let mut updater = updater(serving(&data));
+        let mut slot = MemSink::new(1 << 20);
+        let mut progress = MemProgressStore::new();
+
+        updater.transport.cut_after(Some(120_000));
+        let e = updater
+            .ota_update(&update, &mut slot, &mut progress)
+            .unwrap_err();
+        assert!(matches!(e, OtaError::Incomplete { .. }));
+        assert!(e.is_transient());
+        assert!(!slot.is_committed());
+        assert_eq!(progress.load().unwrap().unwrap().written, 120_000);
+
+        // Only the 80 000 bytes left fit before the cut, so this only
+        // completes when resumed.
+        updater
+            .ota_update(&update, &mut slot, &mut progress)
+            .unwrap();
+        assert!(slot.is_committed());
+        assert_eq!(slot.data(), &data[..]);
+    }
+
+    #[test]
+    fn ignored_range_restarts_download() {
+        let data = image("0.2.0");
+        let update = update_for(&data);
+        let mut updater = updater(serving(&data));
+        let mut slot = MemSink::new(1 << 20);
+        let mut progress = MemProgressStore::new();
+
+        updater.transport.cut_after(Some(120_000));
+        updater
+            .ota_update(&update, &mut slot, &mut progress)
+            .unwrap_err();
+
+        updater.transport.cut_after(None);
+        updater.transport.ignore_ranges(true);
+        updater
+            .ota_update(&update, &mut slot, &mut progress)
+            .unwrap();
+        assert!(slot.is_committed());
+        assert_eq!(slot.data(), &data[..]);
+    }
+
+    #[test]
+    fn digest_mismatch_does_not_commit() {
+        let data = image("0.2.0");
+        let mut update = update_for(&data);
+        update.sha256 = hex::encode(Sha256::digest(b"another image"));
+        let mut updater = updater(serving(&data));
+        let mut slot = MemSink::new(1 << 20);
+        let mut progress = MemProgressStore::new();
+
+        let e = updater
+            .ota_update(&update, &mut slot, &mut progress)
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            OtaError::Integrity(IntegrityError::DigestMismatch { .. })
+        ));
+        assert!(!slot.is_committed());
+        assert!(progress.load().unwrap().is_none());
+    }
+
+    #[test]
+    fn content_length_mismatch_does_not_commit() {
+        let data = image("0.2.0");
+        let mut update = update_for(&data);
+        update.size += 1;
+        let mut updater = updater(serving(&data));
+        let mut slot = MemSink::new(1 << 20);
+        let mut progress = MemProgressStore::new();
+
+        let e = updater
+            .ota_update(&update, &mut slot, &mut progress)
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            OtaError::Integrity(IntegrityError::ContentLengthMismatch { .. })
+        ));
+        assert!(!slot.is_committed());
+        assert!(slot.data().is_empty());
+    }
+
+    /// What downloading `data` from `transport` fails with, and the slot after.
+    fn rejected(transport: MemTransport, data: &[u8]) -> (OtaError, MemSink) {
+        let mut updater = updater(transport);
+        let mut slot = MemSink::new(1 << 20);
+        let e = updater
+            .ota_update(&update_for(data), &mut slot, &mut MemProgressStore::new())
+            .unwrap_err();
+        (e, slot)
+    }
+
+    #[test]
+    fn refused_download_leaves_slot_unwritten() {
+        let data = image("0.2.0");
+        let (e, slot) = rejected(MemTransport::new(), &data);
+        assert!(matches!(e, OtaError::HttpStatus(404)));
+        assert!(slot.data().is_empty());
+
+        let mut bad_magic = data.clone();
+        bad_magic[0] = 0x00;
+        let (e, slot) = rejected(serving(&bad_magic), &bad_magic);
+        assert!(matches!(
+            e,
+            OtaError::Policy(PolicyError::Image(ImageError::BadMagic(0x00)))
+        ));
+        assert!(slot.data().is_empty());
+
+        let mut no_app_desc = data;
+        no_app_desc[32..36].fill(0);
+        let (e, slot) = rejected(serving(&no_app_desc), &no_app_desc);
+        assert!(matches!(
+            e,
+            OtaError::Policy(PolicyError::Image(ImageError::BadAppDescMagic(0)))
+        ));
+        assert!(slot.data().is_empty());
+        assert!(!slot.is_committed());
+    }
+}
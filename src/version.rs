@@ -0,0 +1,94 @@
+use core::ffi::{c_char, CStr};
+use esp_idf_sys::{
+    build_time::build_time_utc, const_format::formatcp, esp_app_desc_t,
+    esp_ota_get_app_description, ESP_APP_DESC_MAGIC_WORD, ESP_IDF_VERSION_MAJOR,
+    ESP_IDF_VERSION_MINOR, ESP_IDF_VERSION_PATCH,
+};
+use log::warn;
+use semver::Version;
+
+const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+#[cfg(esp_idf_bootloader_app_anti_rollback)]
+const SECURE_VERSION: u32 = esp_idf_sys::CONFIG_BOOTLOADER_APP_SECURE_VERSION;
+#[cfg(not(esp_idf_bootloader_app_anti_rollback))]
+const SECURE_VERSION: u32 = 0;
+
+/// The app descriptor of this image, replacing the one ESP-IDF builds, which
+/// names every Rust project `libespidf`. Images identify with the crate name
+/// and version, the updater checks downloads against both.
+#[no_mangle]
+#[used]
+#[link_section = ".rodata_desc"]
+#[allow(non_upper_case_globals)]
+pub static esp_app_desc: esp_app_desc_t = esp_app_desc_t {
+    magic_word: ESP_APP_DESC_MAGIC_WORD,
+    secure_version: SECURE_VERSION,
+    reserv1: [0; 2],
+    version: c_str(PKG_VERSION),
+    project_name: c_str(env!("CARGO_PKG_NAME")),
+    time: c_str(build_time_utc!("%H:%M:%S")),
+    date: c_str(build_time_utc!("%Y-%m-%d")),
+    idf_ver: c_str(formatcp!(
+        "v{}.{}.{}",
+        ESP_IDF_VERSION_MAJOR,
+        ESP_IDF_VERSION_MINOR,
+        ESP_IDF_VERSION_PATCH
+    )),
+    app_elf_sha256: [0; 32],
+    reserv2: [0; 20],
+};
+
+/// `s` as a NUL padded C string, cut to fit.
+const fn c_str<const N: usize>(s: &str) -> [c_char; N] {
+    let mut out = [0; N];
+    let mut i = 0;
+    // Keeps the last byte for the terminating NUL.
+    while i < s.len() && i < N - 1 {
+        out[i] = s.as_bytes()[i] as c_char;
+        i += 1;
+    }
+    out
+}
+
+/// Version of the firmware image we are running from.
+///
+/// The app descriptor of the running partition is preferred, as that is what
+/// the bootloader and other images see. When it does not hold a semver string
+/// the crate version baked in at build time is used instead.
+pub fn running() -> Version {
+    let pkg = Version::parse(PKG_VERSION).expect("CARGO_PKG_VERSION is always semver");
+
+    let desc = unsafe { &*esp_ota_get_app_description() };
+    let raw = unsafe { CStr::from_ptr(desc.version.as_ptr()) }.to_string_lossy();
+
+    match Version::parse(&raw) {
+        Ok(version) => {
+            if version != pkg {
+                warn!(
+                    "App descriptor version {} differs from crate version {}",
+                    version, pkg
+                );
+            }
+            version
+        }
+        Err(_) => pkg,
+    }
+}
+
+/// Lowest app `secure_version` the bootloader still boots, as burnt in eFuse
+/// when anti-rollback is enabled.
+pub fn min_secure_version() -> u32 {
+    #[cfg(esp_idf_bootloader_app_anti_rollback)]
+    return unsafe { esp_idf_sys::esp_efuse_read_secure_version() };
+    #[cfg(not(esp_idf_bootloader_app_anti_rollback))]
+    0
+}
+
+/// Project name from the app descriptor of the running image.
+pub fn project_name() -> String {
+    let desc = unsafe { &*esp_ota_get_app_description() };
+    unsafe { CStr::from_ptr(desc.project_name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
@@ -79,3 +79,10 @@ pub fn wifi(
 
     Ok(Box::new(esp_wifi))
 }
+
+pub fn connected(esp_wifi: &EspWifi<'static>) -> Result<()> {
+    if !esp_wifi.is_connected()? {
+        bail!("Wifi is not connected");
+    }
+    Ok(())
+}